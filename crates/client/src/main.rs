@@ -1,13 +1,15 @@
 use std::path::{Path, PathBuf};
 
 use clap::Parser;
-use color_eyre::{eyre::Result, eyre::WrapErr};
-use log::info;
+use color_eyre::eyre::{bail, Result, WrapErr};
+use log::{error, info, warn};
 use needletail::{parse_fastx_file, parse_fastx_stdin, Sequence};
+use rayon::prelude::*;
+use reqwest::blocking::Client;
+use serde::Deserialize;
 
 use sourmash::encodings::HashFunctions;
-use sourmash::prelude::*;
-use sourmash::signature::Signature;
+use sourmash::signature::{Signature, SigsTrait};
 use sourmash::sketch::minhash::{max_hash_for_scaled, KmerMinHashBTree};
 use sourmash::sketch::Sketch;
 
@@ -38,12 +40,28 @@ static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Cli {
-    /// Input file. Can be:
+    /// Input files, each searched as a separate query. Can be:
     ///   - sequences (FASTA/Q, compressed or not)
-    ///   - an existing signature (use with --sig)
+    ///   - existing signatures (use with --sig)
     ///   - a single dash ("-") for reading from stdin
-    #[clap(parse(from_os_str), verbatim_doc_comment)]
-    sequences: PathBuf,
+    ///   - glob patterns, like "samples/*.fastq.gz"
+    #[clap(
+        parse(from_os_str),
+        verbatim_doc_comment,
+        required_unless_present = "from-file"
+    )]
+    sequences: Vec<PathBuf>,
+
+    /// File with more inputs, one per line.
+    /// Inputs for the same sample go in one line, after the sample name and
+    /// separated by tabs: "sample<TAB>sample_R1.fq.gz<TAB>sample_R2.fq.gz"
+    #[clap(parse(from_os_str), long)]
+    from_file: Option<PathBuf>,
+
+    /// Search all inputs as a single query with this name,
+    /// like the paired-end reads or lanes of one sample
+    #[clap(short, long)]
+    name: Option<String>,
 
     /// Save results to this file. Default: stdout
     #[clap(parse(from_os_str), short, long)]
@@ -53,43 +71,260 @@ struct Cli {
     #[clap(short, long, default_value = "https://mastiff.sourmash.bio")]
     server: String,
 
+    /// Index to search. Default: the server default index
+    #[clap(short, long)]
+    index: Option<String>,
+
     /// Input file is already a signature
     #[clap(long = "sig")]
     is_sig: bool,
+
+    /// Keep k-mer abundances in the query.
+    /// Search results then include abundance-weighted containment.
+    #[clap(long = "abundance")]
+    abundance: bool,
+
+    /// k-mer size. Default: the index ksize
+    #[clap(short, long)]
+    ksize: Option<u32>,
+
+    /// Largest scaled of the sketches used from --sig signatures, at most the index scaled.
+    /// Queries are then downsampled to the index scaled. Default: the index scaled
+    #[clap(long)]
+    scaled: Option<u64>,
+
+    /// Molecule type. Default: the index molecule
+    #[clap(long, possible_values = ["dna", "protein", "dayhoff", "hp"])]
+    molecule: Option<String>,
+
+    /// Search all queries with the batch endpoint, instead of one request per query
+    #[clap(long)]
+    batch: bool,
+
+    /// Number of inputs to sketch in parallel. Default: number of CPUs
+    #[clap(short, long)]
+    jobs: Option<usize>,
 }
 
-fn main() -> Result<()> {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
-    color_eyre::install()?;
+/// Sketch parameters of an index, as reported by the server.
+#[derive(Deserialize, Debug)]
+struct IndexParams {
+    name: String,
+    ksize: u32,
+    scaled: u64,
+    /// Servers not reporting the molecule only support DNA
+    #[serde(default = "default_molecule")]
+    molecule: String,
+    default: bool,
+}
 
-    let Cli {
-        sequences,
-        output,
-        is_sig,
-        server,
-    } = Cli::parse();
+fn default_molecule() -> String {
+    "dna".into()
+}
 
-    info!("Preparing signature");
-    let (sig, query_name): (Signature, String) = if !is_sig {
-        let max_hash = max_hash_for_scaled(1000);
-        let mh = KmerMinHashBTree::builder()
-            .num(0)
-            .max_hash(max_hash)
-            .ksize(21)
-            .build();
-        let mut sig = Signature::builder()
-            .name(Some("mastiff query".into()))
-            .signatures(vec![Sketch::LargeMinHash(mh)])
-            .hash_function("DNA")
-            .build();
+/// Parameters for the query sketch.
+#[derive(Debug)]
+struct QueryParams {
+    /// As stored in sketches, so three times the k-mer size for protein molecules
+    ksize: u32,
+    scaled: u64,
+    /// Largest scaled of the sketches to use from signatures, at most `scaled`
+    sig_scaled: u64,
+    molecule: HashFunctions,
+}
+
+/// An endpoint, as reported by the server.
+#[derive(Deserialize, Debug)]
+struct EndpointInfo {
+    method: String,
+    path: String,
+    max_body_bytes: Option<u64>,
+}
+
+/// Server limits, as reported by `/info`.
+#[derive(Deserialize, Debug)]
+struct ServerLimits {
+    max_batch_size: usize,
+}
+
+/// Server capabilities, as reported by `/info`.
+#[derive(Deserialize, Debug)]
+struct ServerInfo {
+    indexes: Vec<IndexParams>,
+    #[serde(default)]
+    endpoints: Vec<EndpointInfo>,
+    #[serde(default)]
+    limits: Option<ServerLimits>,
+}
+
+impl ServerInfo {
+    /// The index to search, by name or the server default.
+    fn index(&self, server: &str, name: Option<&str>) -> Result<&IndexParams> {
+        let params = match name {
+            Some(name) => self.indexes.iter().find(|idx| idx.name == name),
+            None => self.indexes.iter().find(|idx| idx.default),
+        };
+        match (params, name) {
+            (Some(params), _) => Ok(params),
+            (None, Some(name)) => bail!("Index '{name}' not found in {server}"),
+            (None, None) => bail!("{server} has no default index"),
+        }
+    }
 
-        let (mut parser, mut query_name) = if sequences.as_path() == Path::new("-") {
-            (parse_fastx_stdin()?, None)
+    fn max_body_bytes(&self, method: &str, path: &str) -> Option<u64> {
+        self.endpoints
+            .iter()
+            .find(|e| e.method == method && e.path == path)
+            .and_then(|e| e.max_body_bytes)
+    }
+
+    /// Whether the server has an endpoint. Servers not listing their endpoints
+    /// are assumed to have it.
+    fn has_endpoint(&self, method: &str, path: &str) -> bool {
+        self.endpoints.is_empty()
+            || self
+                .endpoints
+                .iter()
+                .any(|e| e.method == method && e.path == path)
+    }
+}
+
+/// Fetch the server capabilities, or `None` if the server doesn't report them.
+///
+/// Servers without `/info` may still list their indexes in `/indexes`.
+fn fetch_server_info(client: &Client, server: &str) -> Result<Option<ServerInfo>> {
+    let res = client
+        .get(format!("{server}/info"))
+        .send()
+        .wrap_err_with(|| format!("Error connecting to {server}"))?;
+
+    if res.status().is_success() {
+        let info =
+            serde_json::from_slice(&res.bytes()?).wrap_err_with(|| "Error reading server info")?;
+        return Ok(Some(info));
+    }
+
+    let res = client.get(format!("{server}/indexes")).send()?;
+    if !res.status().is_success() {
+        warn!(
+            "Server doesn't report index parameters ({}), using defaults",
+            res.status()
+        );
+        return Ok(None);
+    }
+
+    let indexes = serde_json::from_slice(&res.bytes()?)
+        .wrap_err_with(|| "Error reading index parameters from the server")?;
+    Ok(Some(ServerInfo {
+        indexes,
+        endpoints: vec![],
+        limits: None,
+    }))
+}
+
+/// Combine the parameters requested in the command line with the ones the index supports,
+/// failing if the index can't search them.
+fn query_params(
+    ksize: Option<u32>,
+    scaled: Option<u64>,
+    molecule: Option<&str>,
+    index: Option<&IndexParams>,
+) -> Result<QueryParams> {
+    let molecule = molecule
+        .or(index.map(|idx| idx.molecule.as_str()))
+        .unwrap_or("dna")
+        .to_lowercase();
+    // Protein k-mers are stored as the size of the DNA sequence coding for them
+    let factor = if molecule == "dna" { 1 } else { 3 };
+
+    let Some(index) = index else {
+        let scaled = scaled.unwrap_or(1000);
+        return Ok(QueryParams {
+            ksize: ksize.unwrap_or(21) * factor,
+            scaled,
+            sig_scaled: scaled,
+            molecule: hash_function(&molecule)?,
+        });
+    };
+
+    if molecule != index.molecule.to_lowercase() {
+        bail!(
+            "Index '{}' has {} sketches, can't search {molecule}",
+            index.name,
+            index.molecule
+        );
+    }
+
+    let ksize = ksize.map_or(index.ksize, |k| k * factor);
+    if ksize != index.ksize {
+        bail!(
+            "Index '{}' was built with k={}, can't search k={}",
+            index.name,
+            index.ksize / factor,
+            ksize / factor
+        );
+    }
+
+    let sig_scaled = scaled.unwrap_or(index.scaled);
+    if sig_scaled > index.scaled {
+        bail!(
+            "Index '{}' was built with scaled={}, can't search a larger scaled ({sig_scaled})",
+            index.name,
+            index.scaled
+        );
+    }
+
+    Ok(QueryParams {
+        ksize,
+        scaled: index.scaled,
+        sig_scaled,
+        molecule: hash_function(&molecule)?,
+    })
+}
+
+/// Hash function for a molecule name, as given in `--molecule` or by the server.
+fn hash_function(molecule: &str) -> Result<HashFunctions> {
+    match molecule {
+        "dna" => Ok(HashFunctions::Murmur64Dna),
+        "protein" => Ok(HashFunctions::Murmur64Protein),
+        "dayhoff" => Ok(HashFunctions::Murmur64Dayhoff),
+        "hp" => Ok(HashFunctions::Murmur64Hp),
+        _ => bail!("Unknown molecule '{molecule}', expected one of: dna, protein, dayhoff, hp"),
+    }
+}
+
+/// Sketch sequences from files, or stdin if the path is "-", into a single signature.
+///
+/// Without a `name` the query is named after the first file,
+/// or the first record when reading from stdin.
+fn sketch_sequences(
+    sequences: &[PathBuf],
+    name: Option<&str>,
+    params: &QueryParams,
+    abundance: bool,
+) -> Result<(Signature, String)> {
+    let mh = KmerMinHashBTree::builder()
+        .num(0)
+        .max_hash(max_hash_for_scaled(params.scaled))
+        .ksize(params.ksize)
+        .hash_function(params.molecule.clone())
+        .abunds(abundance.then(Default::default))
+        .build();
+    let mut sig = Signature::builder()
+        .name(Some(name.unwrap_or("mastiff query").into()))
+        .signatures(vec![Sketch::LargeMinHash(mh)])
+        .hash_function(params.molecule.to_string())
+        .build();
+
+    let mut query_name = name.map(String::from);
+    for path in sequences {
+        let mut parser = if path == Path::new("-") {
+            parse_fastx_stdin()?
         } else {
-            (
-                parse_fastx_file(&sequences)?,
-                Some(sequences.to_string_lossy().to_string()),
-            )
+            if query_name.is_none() {
+                query_name = Some(path.to_string_lossy().to_string());
+            }
+            parse_fastx_file(path)?
         };
 
         while let Some(record) = parser.next() {
@@ -100,78 +335,783 @@ fn main() -> Result<()> {
                 query_name = Some(String::from_utf8_lossy(record.id()).to_string());
             }
         }
+    }
 
-        (sig, query_name.expect("Couldn't determine query name"))
-    } else {
-        let mut reader = std::io::BufReader::new(std::fs::File::open(&sequences)?);
-        let mut sigs = Signature::load_signatures(
-            &mut reader,
-            Some(21),
-            Some(HashFunctions::Murmur64Dna),
-            Some(1000),
-        )?;
-
-        let mut sig = sigs.swap_remove(0);
-        // remove abundance
-        let count = sig
-            .iter_mut()
-            .map(|s| {
-                if let Sketch::MinHash(mh) = s {
-                    mh.disable_abundance()
+    match query_name {
+        Some(query_name) => Ok((sig, query_name)),
+        None => bail!("No sequences found in stdin"),
+    }
+}
+
+/// Load a signature compatible with the query parameters.
+///
+/// Signatures can have sketches for several ksizes and molecules, the one with
+/// the largest scaled up to `params.sig_scaled` is used, downsampled to the query scaled.
+fn load_signature(path: &Path, params: &QueryParams, abundance: bool) -> Result<Signature> {
+    let mut reader = std::io::BufReader::new(std::fs::File::open(path)?);
+    // One signature per sketch
+    let sigs = Signature::load_signatures(&mut reader, None, None, None)?;
+    let total = sigs.len();
+
+    let selected = sigs
+        .into_iter()
+        .filter(|sig| {
+            matches!(
+                sig.iter().next(),
+                Some(Sketch::MinHash(mh)) if mh.ksize() == params.ksize as usize
+                    && mh.hash_function() == params.molecule
+                    && mh.scaled() != 0
+                    && mh.scaled() <= params.sig_scaled
+            )
+        })
+        .max_by_key(|sig| match sig.iter().next() {
+            Some(Sketch::MinHash(mh)) => mh.scaled(),
+            _ => 0,
+        });
+
+    let Some(mut sig) = selected else {
+        bail!(
+            "No sketch with k={}, molecule {} and scaled at most {} in {}",
+            params.ksize,
+            params.molecule,
+            params.sig_scaled,
+            path.display()
+        )
+    };
+
+    let Some(Sketch::MinHash(mh)) = sig.iter_mut().next() else {
+        unreachable!("Only MinHash sketches are selected")
+    };
+    if total > 1 {
+        info!(
+            "Using the k={} scaled={} sketch out of {total} in {}",
+            mh.ksize(),
+            mh.scaled(),
+            path.display()
+        );
+    }
+    if mh.scaled() < params.scaled {
+        info!(
+            "Downsampling {} from scaled={} to scaled={}",
+            path.display(),
+            mh.scaled(),
+            params.scaled
+        );
+        *mh = mh.downsample_scaled(params.scaled)?;
+    }
+    if !abundance {
+        mh.disable_abundance();
+    }
+    Ok(sig)
+}
+
+/// Inputs searched together as one query, like paired-end reads or
+/// the lanes of a sequencing run.
+struct Sample {
+    /// Query name, derived from the inputs if not set
+    name: Option<String>,
+    inputs: Vec<PathBuf>,
+}
+
+/// Expand glob patterns in the inputs and add the ones listed in `from_file`,
+/// each input as a separate sample.
+///
+/// Lines in `from_file` can also define samples with several inputs, as a name
+/// followed by the inputs, separated by tabs. With `name` all inputs are
+/// a single sample instead.
+///
+/// Patterns are usually expanded by the shell, but quoting them avoids
+/// argument limits with many files.
+fn collect_samples(
+    sequences: Vec<PathBuf>,
+    from_file: Option<&Path>,
+    name: Option<String>,
+) -> Result<Vec<Sample>> {
+    let mut samples = vec![];
+    for path in sequences {
+        let pattern = path.to_string_lossy();
+        if path.exists() || !pattern.contains(['*', '?', '[']) {
+            samples.push(Sample {
+                name: None,
+                inputs: vec![path],
+            });
+            continue;
+        }
+
+        let matches = glob::glob(&pattern)
+            .wrap_err_with(|| format!("Invalid pattern '{pattern}'"))?
+            .collect::<Result<Vec<_>, _>>()?;
+        if matches.is_empty() {
+            bail!("No files matching '{pattern}'");
+        }
+        samples.extend(matches.into_iter().map(|path| Sample {
+            name: None,
+            inputs: vec![path],
+        }));
+    }
+
+    if let Some(from_file) = from_file {
+        let list = std::fs::read_to_string(from_file)
+            .wrap_err_with(|| format!("Error reading {}", from_file.display()))?;
+        for line in list.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split('\t').map(str::trim).filter(|f| !f.is_empty());
+            let first = fields.next().unwrap_or_default();
+            let inputs: Vec<PathBuf> = fields.map(PathBuf::from).collect();
+            samples.push(if inputs.is_empty() {
+                Sample {
+                    name: None,
+                    inputs: vec![PathBuf::from(first)],
                 }
-            })
-            .count();
-        debug_assert_eq!(count, 1);
+            } else {
+                Sample {
+                    name: Some(first.into()),
+                    inputs,
+                }
+            });
+        }
+    }
+
+    let stdin = samples
+        .iter()
+        .flat_map(|s| &s.inputs)
+        .filter(|p| *p == Path::new("-"))
+        .count();
+    if stdin > 1 {
+        bail!("stdin (\"-\") can only be used once");
+    }
+
+    match name {
+        Some(name) => Ok(vec![Sample {
+            name: Some(name),
+            inputs: samples.into_iter().flat_map(|s| s.inputs).collect(),
+        }]),
+        None => Ok(samples),
+    }
+}
+
+/// A query to search, and the name identifying it in the results.
+struct Query {
+    name: String,
+    sig: Signature,
+}
 
-        (sig, sequences.to_string_lossy().to_string())
+fn prepare_query(
+    sample: &Sample,
+    is_sig: bool,
+    params: &QueryParams,
+    abundance: bool,
+) -> Result<Query> {
+    if !is_sig {
+        let (sig, name) =
+            sketch_sequences(&sample.inputs, sample.name.as_deref(), params, abundance)?;
+        return Ok(Query { name, sig });
+    }
+
+    // Signatures for the same sample are merged into the first one
+    let mut sig: Option<Signature> = None;
+    for input in &sample.inputs {
+        let other = load_signature(input, params, abundance)?;
+        let Some(merged) = sig.as_mut() else {
+            sig = Some(other);
+            continue;
+        };
+        if let (Some(Sketch::MinHash(mh)), Some(Sketch::MinHash(other_mh))) =
+            (merged.iter_mut().next(), other.iter().next())
+        {
+            mh.merge(other_mh)
+                .wrap_err_with(|| format!("Error merging {}", input.display()))?;
+        }
+    }
+    let Some(mut sig) = sig else {
+        bail!("No inputs for sample");
     };
 
-    let output: Box<dyn std::io::Write> = match output {
-        Some(path) => Box::new(std::io::BufWriter::new(
-            std::fs::File::create(path).unwrap(),
-        )),
-        None => Box::new(std::io::stdout()),
+    let name = match &sample.name {
+        Some(name) => {
+            sig.set_name(name);
+            name.clone()
+        }
+        None => sample.inputs[0].to_string_lossy().to_string(),
     };
+    Ok(Query { name, sig })
+}
 
-    let mut sig_data = vec![];
+/// Gzipped JSON for signatures, as sourmash saves them.
+fn encode_sigs(sigs: &[&Signature]) -> Result<Vec<u8>> {
+    let mut data = vec![];
     {
         let mut output = niffler::get_writer(
-            Box::new(&mut sig_data),
+            Box::new(&mut data),
             niffler::compression::Format::Gzip,
             niffler::compression::Level::Nine,
         )
         .wrap_err_with(|| "Error preparing signature")?;
 
-        sig.to_writer(&mut output)
-            .wrap_err_with(|| "Error preparing signature")?;
+        serde_json::to_writer(&mut output, sigs).wrap_err_with(|| "Error preparing signature")?;
+    }
+    Ok(data)
+}
+
+fn check_body_size(data: &[u8], max_body_bytes: Option<u64>) -> Result<()> {
+    match max_body_bytes {
+        Some(max) if data.len() as u64 > max => bail!(
+            "Query is too large for the server ({} bytes, at most {max} are accepted)",
+            data.len()
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Search a single query, returning the CSV header and matches.
+fn search(
+    client: &Client,
+    url: &str,
+    query: &Query,
+    max_body_bytes: Option<u64>,
+) -> Result<(csv::StringRecord, Vec<csv::StringRecord>)> {
+    let sig_data = encode_sigs(&[&query.sig])?;
+    check_body_size(&sig_data, max_body_bytes)?;
+
+    let res = client.post(url).body(sig_data).send()?;
+    if !res.status().is_success() {
+        bail!("{}: {}", res.status(), res.text()?);
+    }
+
+    // Anything the server did to the query to search it, like downsampling
+    if let Some(adjustments) = res
+        .headers()
+        .get("x-query-adjustments")
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty())
+    {
+        info!("{}: {adjustments}", query.name);
+    }
+
+    let data = res.bytes()?;
+    let mut rdr = csv::Reader::from_reader(&data[..]);
+    let headers = rdr.headers()?.clone();
+    let records = rdr.records().collect::<Result<_, _>>()?;
+    Ok((headers, records))
+}
+
+/// Columns for matches in batch results, the same as the server CSV results.
+const BATCH_COLUMNS: &[&str] = &[
+    "SRA accession",
+    "containment",
+    "containment_ani",
+    "max_containment",
+    "max_containment_ani",
+    "jaccard",
+    "weighted_containment",
+];
+
+#[derive(Deserialize)]
+struct BatchResults {
+    /// In the same order as the queries
+    results: Vec<BatchResult>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BatchResult {
+    Ok(SearchResults),
+    Err { name: String, error: String },
+}
+
+#[derive(Deserialize)]
+struct SearchResults {
+    query: BatchQuery,
+    matches: Vec<SearchMatch>,
+}
+
+#[derive(Deserialize)]
+struct BatchQuery {
+    #[serde(default)]
+    adjustments: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct SearchMatch {
+    accession: String,
+    containment: f64,
+    #[serde(default)]
+    containment_ani: Option<f64>,
+    #[serde(default)]
+    max_containment: Option<f64>,
+    #[serde(default)]
+    max_containment_ani: Option<f64>,
+    #[serde(default)]
+    jaccard: Option<f64>,
+    #[serde(default)]
+    weighted_containment: Option<f64>,
+}
+
+impl SearchMatch {
+    fn to_record(&self) -> csv::StringRecord {
+        let optional = |v: Option<f64>| v.map(|v| v.to_string()).unwrap_or_default();
+        csv::StringRecord::from(vec![
+            self.accession.clone(),
+            self.containment.to_string(),
+            optional(self.containment_ani),
+            optional(self.max_containment),
+            optional(self.max_containment_ani),
+            optional(self.jaccard),
+            optional(self.weighted_containment),
+        ])
+    }
+}
+
+/// Search queries in one batch request, returning the matches for each query
+/// in the same order, or why it failed.
+fn search_batch(
+    client: &Client,
+    url: &str,
+    queries: &[Query],
+    max_body_bytes: Option<u64>,
+) -> Result<Vec<Result<Vec<csv::StringRecord>, String>>> {
+    let sigs: Vec<&Signature> = queries.iter().map(|q| &q.sig).collect();
+    let data = encode_sigs(&sigs)?;
+    check_body_size(&data, max_body_bytes).wrap_err_with(|| "Try a smaller batch")?;
+
+    let res = client.post(url).body(data).send()?;
+    if !res.status().is_success() {
+        bail!("{}: {}", res.status(), res.text()?);
+    }
+    let batch: BatchResults =
+        serde_json::from_slice(&res.bytes()?).wrap_err_with(|| "Error reading batch results")?;
+
+    if batch.results.len() != queries.len() {
+        bail!(
+            "Server returned results for {} queries, expected {}",
+            batch.results.len(),
+            queries.len()
+        );
+    }
+
+    Ok(queries
+        .iter()
+        .zip(&batch.results)
+        .map(|(query, result)| match result {
+            BatchResult::Ok(results) => {
+                for adjustment in &results.query.adjustments {
+                    info!("{}: {adjustment}", query.name);
+                }
+                Ok(results.matches.iter().map(|m| m.to_record()).collect())
+            }
+            BatchResult::Err { name, error } => Err(format!("{name}: {error}")),
+        })
+        .collect())
+}
+
+/// Combined results for all queries, with a `query` column identifying each one.
+struct ResultsWriter<W: std::io::Write> {
+    wtr: csv::Writer<W>,
+    has_header: bool,
+}
+
+impl<W: std::io::Write> ResultsWriter<W> {
+    fn new(output: W) -> Self {
+        ResultsWriter {
+            wtr: csv::Writer::from_writer(output),
+            has_header: false,
+        }
+    }
+
+    /// Write the header, if not written yet.
+    fn header(&mut self, header: &csv::StringRecord) -> Result<()> {
+        if !self.has_header {
+            let mut header = header.clone();
+            header.push_field("query");
+            self.wtr.write_record(&header)?;
+            self.has_header = true;
+        }
+        Ok(())
     }
 
-    info!("Sending request to {}", server);
-    let client = reqwest::blocking::Client::builder()
+    fn write(&mut self, query: &str, records: Vec<csv::StringRecord>) -> Result<()> {
+        for mut record in records {
+            record.push_field(query);
+            self.wtr.write_record(&record)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(self.wtr.flush()?)
+    }
+}
+
+fn main() -> Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    color_eyre::install()?;
+
+    let Cli {
+        sequences,
+        from_file,
+        name,
+        output,
+        server,
+        index,
+        is_sig,
+        abundance,
+        ksize,
+        scaled,
+        molecule,
+        batch,
+        jobs,
+    } = Cli::parse();
+
+    let samples = collect_samples(sequences, from_file.as_deref(), name)?;
+    if let Some(jobs) = jobs {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .build_global()?;
+    }
+
+    let client = Client::builder()
         .timeout(std::time::Duration::from_secs(3600))
         .build()?;
-    let res = client
-        .post(format!("{}/search", server))
-        .body(sig_data)
-        .send()?;
 
-    info!("Writing matches to output");
-    let data = res.bytes()?;
+    info!("Checking index parameters in {}", server);
+    let server_info = fetch_server_info(&client, &server)?;
+    let index_params = match &server_info {
+        Some(info) => Some(info.index(&server, index.as_deref())?),
+        None => None,
+    };
+    let params = query_params(ksize, scaled, molecule.as_deref(), index_params)?;
 
-    let mut wtr = csv::Writer::from_writer(output);
-    let mut rdr = csv::Reader::from_reader(&data[..]);
+    info!("Preparing {} queries", samples.len());
+    let queries = samples
+        .par_iter()
+        .map(|sample| {
+            prepare_query(sample, is_sig, &params, abundance).wrap_err_with(|| {
+                let name = match &sample.name {
+                    Some(name) => name.clone(),
+                    None => sample.inputs[0].display().to_string(),
+                };
+                format!("Error preparing query for {name}")
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
 
-    let mut headers = rdr.headers()?.clone();
-    headers.push_field("query");
+    let output: Box<dyn std::io::Write> = match output {
+        Some(path) => Box::new(std::io::BufWriter::new(
+            std::fs::File::create(path).unwrap(),
+        )),
+        None => Box::new(std::io::stdout()),
+    };
+    let mut results = ResultsWriter::new(output);
+
+    let batch_supported = server_info.as_ref().map_or(true, |info| {
+        info.has_endpoint("POST", "/batch/search[/{index}]")
+    });
+    if batch && !batch_supported {
+        warn!("{server} doesn't support batch searches, sending one request per query");
+    }
+
+    let endpoint = if batch && batch_supported {
+        "batch/search"
+    } else {
+        "search"
+    };
+    let url = match &index {
+        Some(index) => format!("{server}/{endpoint}/{index}"),
+        None => format!("{server}/{endpoint}"),
+    };
 
-    wtr.write_record(&headers)?;
+    let mut failed = 0;
+    if batch && batch_supported {
+        let max_body_bytes = server_info
+            .as_ref()
+            .and_then(|info| info.max_body_bytes("POST", "/batch/search[/{index}]"));
+        let batch_size = server_info
+            .as_ref()
+            .and_then(|info| info.limits.as_ref())
+            .map_or(queries.len(), |limits| limits.max_batch_size)
+            .max(1);
 
-    for result in rdr.records() {
-        let mut record = result?;
-        record.push_field(query_name.as_str());
-        wtr.write_record(&record)?;
+        results.header(&csv::StringRecord::from(BATCH_COLUMNS.to_vec()))?;
+        for chunk in queries.chunks(batch_size) {
+            info!("Sending {} queries to {}", chunk.len(), server);
+            // A failed request only fails the queries in this chunk
+            let matches = match search_batch(&client, &url, chunk, max_body_bytes) {
+                Ok(matches) => matches,
+                Err(e) => {
+                    for query in chunk {
+                        error!("Error searching {}: {e}", query.name);
+                    }
+                    failed += chunk.len();
+                    continue;
+                }
+            };
+            for (query, matches) in chunk.iter().zip(matches) {
+                match matches {
+                    Ok(matches) => results.write(&query.name, matches)?,
+                    Err(e) => {
+                        error!("Error searching {}: {e}", query.name);
+                        failed += 1;
+                    }
+                }
+            }
+        }
+    } else {
+        let max_body_bytes = server_info
+            .as_ref()
+            .and_then(|info| info.max_body_bytes("POST", "/search[/{index}]"));
+
+        for query in &queries {
+            info!("Searching {} in {}", query.name, server);
+            match search(&client, &url, query, max_body_bytes) {
+                Ok((header, matches)) => {
+                    results.header(&header)?;
+                    results.write(&query.name, matches)?;
+                }
+                Err(e) => {
+                    error!("Error searching {}: {e}", query.name);
+                    failed += 1;
+                }
+            }
+        }
     }
+    results.flush()?;
 
+    if failed > 0 {
+        bail!("{failed} of {} queries failed", queries.len());
+    }
     info!("Finished!");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use sourmash::sketch::minhash::KmerMinHash;
+
+    use super::*;
+
+    /// Empty directory for a test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("mastiff-client-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn index(ksize: u32, scaled: u64, molecule: &str) -> IndexParams {
+        IndexParams {
+            name: "sra".into(),
+            ksize,
+            scaled,
+            molecule: molecule.into(),
+            default: true,
+        }
+    }
+
+    fn params(ksize: u32, scaled: u64, sig_scaled: u64) -> QueryParams {
+        QueryParams {
+            ksize,
+            scaled,
+            sig_scaled,
+            molecule: HashFunctions::Murmur64Dna,
+        }
+    }
+
+    fn inputs(samples: &[Sample]) -> Vec<Vec<PathBuf>> {
+        samples.iter().map(|s| s.inputs.clone()).collect()
+    }
+
+    #[test]
+    fn collect_paths() {
+        let samples = collect_samples(vec!["a.fa".into(), "-".into()], None, None).unwrap();
+        assert_eq!(
+            inputs(&samples),
+            vec![vec![PathBuf::from("a.fa")], vec![PathBuf::from("-")]]
+        );
+        assert!(samples.iter().all(|s| s.name.is_none()));
+    }
+
+    #[test]
+    fn collect_patterns() {
+        let dir = test_dir("patterns");
+        for file in ["s2.fa", "s1.fa", "notes.txt"] {
+            std::fs::write(dir.join(file), ">r\nACGT\n").unwrap();
+        }
+
+        let samples = collect_samples(vec![dir.join("*.fa")], None, None).unwrap();
+        assert_eq!(
+            inputs(&samples),
+            vec![vec![dir.join("s1.fa")], vec![dir.join("s2.fa")]]
+        );
+        assert!(collect_samples(vec![dir.join("*.fq")], None, None).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn collect_from_file() {
+        let dir = test_dir("from-file");
+        let list = dir.join("inputs.txt");
+        std::fs::write(
+            &list,
+            "# samples\nsingle.fa\n\nsample\tsample_R1.fq\tsample_R2.fq\n",
+        )
+        .unwrap();
+
+        let samples = collect_samples(vec!["first.fa".into()], Some(&list), None).unwrap();
+        assert_eq!(
+            inputs(&samples),
+            vec![
+                vec![PathBuf::from("first.fa")],
+                vec![PathBuf::from("single.fa")],
+                vec![PathBuf::from("sample_R1.fq"), PathBuf::from("sample_R2.fq")],
+            ]
+        );
+        assert_eq!(samples[1].name, None);
+        assert_eq!(samples[2].name.as_deref(), Some("sample"));
+
+        assert!(collect_samples(vec![], Some(&dir.join("missing.txt")), None).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn collect_with_name() {
+        let samples = collect_samples(
+            vec!["lane1.fq".into(), "lane2.fq".into()],
+            None,
+            Some("run".into()),
+        )
+        .unwrap();
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].name.as_deref(), Some("run"));
+        assert_eq!(
+            samples[0].inputs,
+            vec![PathBuf::from("lane1.fq"), PathBuf::from("lane2.fq")]
+        );
+    }
+
+    #[test]
+    fn collect_stdin_once() {
+        assert!(collect_samples(vec!["-".into(), "-".into()], None, None).is_err());
+    }
+
+    #[test]
+    fn query_params_without_index() {
+        let params = query_params(None, None, None, None).unwrap();
+        assert_eq!(
+            (params.ksize, params.scaled, params.sig_scaled),
+            (21, 1000, 1000)
+        );
+        assert_eq!(params.molecule, HashFunctions::Murmur64Dna);
+
+        let params = query_params(Some(10), Some(100), Some("protein"), None).unwrap();
+        assert_eq!((params.ksize, params.scaled), (30, 100));
+        assert_eq!(params.molecule, HashFunctions::Murmur64Protein);
+    }
+
+    #[test]
+    fn query_params_from_index() {
+        let sra = index(31, 1000, "DNA");
+        let params = query_params(None, None, None, Some(&sra)).unwrap();
+        assert_eq!(
+            (params.ksize, params.scaled, params.sig_scaled),
+            (31, 1000, 1000)
+        );
+
+        // Signatures can have a smaller scaled, they are downsampled to the index one
+        let params = query_params(Some(31), Some(100), Some("dna"), Some(&sra)).unwrap();
+        assert_eq!((params.scaled, params.sig_scaled), (1000, 100));
+
+        let protein = index(30, 100, "protein");
+        let params = query_params(Some(10), None, None, Some(&protein)).unwrap();
+        assert_eq!(params.ksize, 30);
+        assert_eq!(params.molecule, HashFunctions::Murmur64Protein);
+    }
+
+    #[test]
+    fn query_params_incompatible() {
+        let sra = index(31, 1000, "DNA");
+        let err = |ksize, scaled, molecule| {
+            query_params(ksize, scaled, molecule, Some(&sra))
+                .unwrap_err()
+                .to_string()
+        };
+        assert!(err(Some(21), None, None).contains("built with k=31, can't search k=21"));
+        assert!(err(None, Some(10000), None).contains("can't search a larger scaled (10000)"));
+        assert!(err(None, None, Some("protein")).contains("can't search protein"));
+    }
+
+    #[test]
+    fn query_params_unknown_molecule() {
+        let err = query_params(None, None, None, Some(&index(31, 1000, "skipm1n3")))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("Unknown molecule 'skipm1n3'"));
+        assert!(query_params(None, None, Some("rna"), None).is_err());
+    }
+
+    /// Signature file with DNA sketches for each `(ksize, scaled)`, tracking abundances
+    fn signature_file(dir: &Path, sketches: &[(u32, u64)]) -> PathBuf {
+        let sketches = sketches
+            .iter()
+            .map(|&(ksize, scaled)| {
+                let mut mh =
+                    KmerMinHash::new(scaled, ksize, HashFunctions::Murmur64Dna, 42, true, 0);
+                for hash in [1, 2, max_hash_for_scaled(1000) + 1] {
+                    mh.add_hash_with_abundance(hash, 3);
+                }
+                Sketch::MinHash(mh)
+            })
+            .collect();
+        let sig = Signature::builder()
+            .name(Some("query".into()))
+            .signatures(sketches)
+            .hash_function("DNA")
+            .build();
+        let path = dir.join("query.sig");
+        std::fs::write(&path, serde_json::to_string(&[sig]).unwrap()).unwrap();
+        path
+    }
+
+    fn minhash(sig: &Signature) -> &KmerMinHash {
+        match sig.iter().next() {
+            Some(Sketch::MinHash(mh)) => mh,
+            _ => panic!("No MinHash sketch"),
+        }
+    }
+
+    #[test]
+    fn load_signature_selects_sketch() {
+        let dir = test_dir("load-signature");
+        let path = signature_file(&dir, &[(21, 1000), (31, 100), (31, 1000)]);
+
+        // The largest scaled allowed is used, without downsampling
+        let sig = load_signature(&path, &params(31, 1000, 1000), true).unwrap();
+        assert_eq!((minhash(&sig).ksize(), minhash(&sig).scaled()), (31, 1000));
+        assert_eq!(minhash(&sig).abunds(), Some(vec![3, 3]));
+
+        // A smaller scaled is downsampled to the query scaled
+        let sig = load_signature(&path, &params(31, 1000, 500), false).unwrap();
+        assert_eq!(minhash(&sig).scaled(), 1000);
+        assert_eq!(minhash(&sig).mins(), vec![1, 2]);
+        assert_eq!(minhash(&sig).abunds(), None);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn load_signature_without_compatible_sketch() {
+        let dir = test_dir("load-signature-missing");
+        let path = signature_file(&dir, &[(31, 1000)]);
+
+        let err = load_signature(&path, &params(21, 1000, 1000), true).unwrap_err();
+        assert!(err.to_string().contains("No sketch with k=21"));
+        // Sketches with a scaled over sig_scaled can't be used
+        assert!(load_signature(&path, &params(31, 100, 100), true).is_err());
+        assert!(load_signature(&dir.join("missing.sig"), &params(31, 1000, 1000), true).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
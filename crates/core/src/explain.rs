@@ -0,0 +1,184 @@
+//! Find which query hashes are shared with a dataset in the index.
+//!
+//! The RevIndex only reports how many hashes each dataset shares with a query,
+//! so the dataset signature is loaded the same way gather loads its matches
+//! and intersected with the query. This takes one lookup per query hash,
+//! like a search, and reading one signature from the index storage.
+
+use sourmash::index::revindex::{RevIndex, RevIndexOps};
+use sourmash::signature::{Signature, SigsTrait};
+use sourmash::sketch::minhash::KmerMinHash;
+use sourmash::sketch::Sketch;
+
+use crate::names::NameResolver;
+
+#[derive(Debug)]
+pub enum ExplainError {
+    /// No dataset sharing hashes with the query has this name or accession
+    NotFound(String),
+    /// More than one dataset has this accession
+    Ambiguous(String, Vec<String>),
+    /// The dataset signature has no sketch with the query ksize
+    MissingKsize(String, usize),
+    /// Error loading the dataset signature or comparing it with the query
+    Index(sourmash::Error),
+}
+
+impl std::fmt::Display for ExplainError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExplainError::NotFound(dataset) => {
+                write!(
+                    f,
+                    "No dataset named '{dataset}' shares hashes with the query"
+                )
+            }
+            ExplainError::Ambiguous(dataset, names) => write!(
+                f,
+                "'{dataset}' matches more than one dataset: {}",
+                names.join(", ")
+            ),
+            ExplainError::MissingKsize(dataset, ksize) => {
+                write!(f, "Dataset '{dataset}' has no sketch with k={ksize}")
+            }
+            ExplainError::Index(e) => write!(f, "Error comparing with the dataset: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ExplainError {}
+
+/// Hashes shared by the query and a dataset, and the dataset name in the index.
+pub struct Explanation {
+    pub dataset: String,
+    pub hashes: Vec<u64>,
+}
+
+/// Find the hashes `query` shares with `dataset`, given by name or accession.
+pub fn explain(
+    db: &RevIndex,
+    query: &KmerMinHash,
+    dataset: &str,
+    names: &NameResolver,
+) -> Result<Explanation, ExplainError> {
+    let (counter, query_colors, hash_to_color) = db.prepare_gather_counters(query);
+
+    // Names are only available from counters, so each dataset gets its own
+    // single-entry counter. It is also used to gather only the selected dataset.
+    let candidates: Vec<_> = counter
+        .iter()
+        .filter_map(|(&dataset_id, &size)| {
+            let single = std::iter::once((dataset_id, size)).collect();
+            let (name, _) = db.matches_from_counter(single, 1).pop()?;
+            (name == dataset || names.resolve(&name) == dataset).then_some((name, dataset_id, size))
+        })
+        .collect();
+
+    let (name, dataset_id, size) = match candidates.len() {
+        0 => return Err(ExplainError::NotFound(dataset.into())),
+        1 => candidates.into_iter().next().unwrap(),
+        _ => {
+            // An exact name match is never ambiguous
+            match candidates.iter().position(|(name, _, _)| name == dataset) {
+                Some(pos) => candidates.into_iter().nth(pos).unwrap(),
+                None => {
+                    return Err(ExplainError::Ambiguous(
+                        dataset.into(),
+                        candidates.into_iter().map(|(name, _, _)| name).collect(),
+                    ))
+                }
+            }
+        }
+    };
+
+    let matches = db
+        .gather(
+            std::iter::once((dataset_id, size)).collect(),
+            query_colors,
+            hash_to_color,
+            0,
+            query,
+            None,
+        )
+        .map_err(ExplainError::Index)?;
+    let Some(found) = matches.first() else {
+        return Err(ExplainError::NotFound(dataset.into()));
+    };
+
+    let hashes = shared_hashes(query, &found.get_match())?;
+    Ok(Explanation {
+        dataset: name,
+        hashes,
+    })
+}
+
+/// Hashes in both `query` and the sketch in `matched`, sorted.
+fn shared_hashes(query: &KmerMinHash, matched: &Signature) -> Result<Vec<u64>, ExplainError> {
+    let Some(mh) = matched.iter().find_map(|sketch| match sketch {
+        Sketch::MinHash(mh) if mh.ksize() == query.ksize() => Some(mh),
+        _ => None,
+    }) else {
+        return Err(ExplainError::MissingKsize(matched.name(), query.ksize()));
+    };
+
+    let intersection = if mh.scaled() < query.scaled() {
+        mh.downsample_scaled(query.scaled())
+            .and_then(|mh| mh.intersection(query))
+    } else {
+        query
+            .downsample_scaled(mh.scaled())
+            .and_then(|query| query.intersection(mh))
+    };
+    let (hashes, _) = intersection.map_err(ExplainError::Index)?;
+    Ok(hashes)
+}
+
+#[cfg(test)]
+mod tests {
+    use sourmash::encodings::HashFunctions;
+
+    use super::*;
+
+    fn sketch(scaled: u64, ksize: u32, hashes: &[u64]) -> KmerMinHash {
+        let mut mh = KmerMinHash::new(scaled, ksize, HashFunctions::Murmur64Dna, 42, false, 0);
+        mh.add_many(hashes).unwrap();
+        mh
+    }
+
+    fn signature(mh: KmerMinHash) -> Signature {
+        Signature::builder()
+            .name(Some("SRR123456".into()))
+            .signatures(vec![Sketch::MinHash(mh)])
+            .hash_function("DNA")
+            .build()
+    }
+
+    #[test]
+    fn shared_hashes_same_scaled() {
+        let query = sketch(1, 21, &[1, 2, 3, 5]);
+        let matched = signature(sketch(1, 21, &[5, 3, 4]));
+        assert_eq!(shared_hashes(&query, &matched).unwrap(), vec![3, 5]);
+    }
+
+    #[test]
+    fn shared_hashes_downsampled() {
+        // Hashes over the max_hash for scaled=2 are not shared
+        let max_hash = u64::MAX / 2;
+        let query = sketch(2, 21, &[1, 2, max_hash - 1]);
+        let matched = signature(sketch(1, 21, &[1, max_hash - 1, max_hash + 10]));
+        assert_eq!(
+            shared_hashes(&query, &matched).unwrap(),
+            vec![1, max_hash - 1]
+        );
+    }
+
+    #[test]
+    fn shared_hashes_missing_ksize() {
+        let query = sketch(1, 21, &[1, 2]);
+        let matched = signature(sketch(1, 31, &[1, 2]));
+        assert!(matches!(
+            shared_hashes(&query, &matched),
+            Err(ExplainError::MissingKsize(name, 21)) if name == "SRR123456"
+        ));
+    }
+}
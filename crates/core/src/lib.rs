@@ -0,0 +1,6 @@
+//! Functionality shared by the mastiff server and index tools.
+
+pub mod explain;
+pub mod lookup;
+pub mod names;
+pub mod stats;
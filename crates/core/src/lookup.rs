@@ -0,0 +1,59 @@
+//! Find which datasets in the index contain a hash.
+
+use std::collections::BTreeMap;
+
+use sourmash::index::revindex::{RevIndex, RevIndexOps};
+use sourmash::sketch::minhash::KmerMinHash;
+
+use crate::names::NameResolver;
+
+#[derive(Debug)]
+pub enum LookupError {
+    /// The hash is over the max_hash for the index scaled, so it can't be in the index
+    OutOfRange(u64, u64),
+}
+
+impl std::fmt::Display for LookupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LookupError::OutOfRange(hash, scaled) => {
+                write!(f, "Hash {hash} is not kept with scaled={scaled}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for LookupError {}
+
+/// Accessions of the datasets containing each hash, as resolved by `names`.
+///
+/// `template` is a sketch with the same parameters as the index.
+pub fn lookup(
+    db: &RevIndex,
+    template: &KmerMinHash,
+    hashes: &[u64],
+    names: &NameResolver,
+) -> Result<BTreeMap<u64, Vec<String>>, LookupError> {
+    let mut template = template.clone();
+    template.clear();
+
+    let mut datasets = BTreeMap::new();
+    for &hash in hashes {
+        if hash > template.max_hash() {
+            return Err(LookupError::OutOfRange(hash, template.scaled()));
+        }
+
+        let mut query = template.clone();
+        query.add_hash(hash);
+
+        let counter = db.counter_for_query(&query);
+        let accessions = db
+            .matches_from_counter(counter, 1)
+            .into_iter()
+            .map(|(name, _)| names.resolve(&name).to_string())
+            .collect();
+        datasets.insert(hash, accessions);
+    }
+
+    Ok(datasets)
+}
@@ -0,0 +1,216 @@
+//! Resolve dataset names in an index into accessions.
+//!
+//! The RevIndex reports matches by signature name, which depending on how the
+//! signatures were built can be an accession (`SRR123456`), a path
+//! (`sigs/SRR123456.sig`) or a description (`GCF_000195915.1 Helicobacter pylori`).
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use regex::Regex;
+use serde::Deserialize;
+use sourmash::manifest::Manifest;
+
+/// Extensions removed from file names, longest first.
+const SIG_EXTENSIONS: &[&str] = &[".sig.gz", ".sig.zip", ".json.gz", ".sig", ".json", ".zip"];
+
+/// Where to take the accession from.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NameSource {
+    /// The signature name, unchanged
+    Name,
+    /// The file name in the signature name, without signature extensions.
+    /// Manifest locations are not used, see `Location` for those.
+    #[default]
+    Filename,
+    /// The manifest `internal_location`
+    Location,
+}
+
+impl FromStr for NameSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "name" => Ok(NameSource::Name),
+            "filename" => Ok(NameSource::Filename),
+            "location" => Ok(NameSource::Location),
+            _ => Err(format!(
+                "Unknown name source '{s}', expected one of: name, filename, location"
+            )),
+        }
+    }
+}
+
+/// Turns the dataset names reported by the index into accessions.
+///
+/// A `pattern` is applied to the name taken from the source: the `accession`
+/// group is used if defined, otherwise the first group or the whole match.
+/// Names not matching the pattern are used unchanged.
+#[derive(Debug, Clone, Default)]
+pub struct NameResolver {
+    source: NameSource,
+    pattern: Option<Regex>,
+    /// Manifest `internal_location` for each signature name
+    locations: HashMap<String, String>,
+}
+
+impl NameResolver {
+    pub fn new(source: NameSource, pattern: Option<&str>) -> Result<Self, regex::Error> {
+        let pattern = pattern.map(Regex::new).transpose()?;
+        Ok(NameResolver {
+            source,
+            pattern,
+            locations: HashMap::new(),
+        })
+    }
+
+    /// Use the locations in a manifest, so they can be resolved from signature names.
+    pub fn with_manifest(mut self, manifest: &Manifest) -> Self {
+        self.locations = manifest
+            .iter()
+            .map(|r| (r.name().clone(), r.internal_location().to_string()))
+            .collect();
+        self
+    }
+
+    pub fn source(&self) -> NameSource {
+        self.source
+    }
+
+    /// Whether locations are available from a manifest.
+    pub fn has_locations(&self) -> bool {
+        !self.locations.is_empty()
+    }
+
+    /// Accession for a dataset name reported by the index.
+    pub fn resolve<'a>(&'a self, name: &'a str) -> &'a str {
+        let location = self.locations.get(name).map(|l| l.as_str());
+        let value = match self.source {
+            NameSource::Name => name,
+            NameSource::Filename => file_name(name),
+            NameSource::Location => location.unwrap_or(name),
+        };
+
+        let Some(captures) = self.pattern.as_ref().and_then(|p| p.captures(value)) else {
+            return value;
+        };
+        captures
+            .name("accession")
+            .or_else(|| captures.get(1))
+            .or_else(|| captures.get(0))
+            .map_or(value, |m| m.as_str())
+    }
+}
+
+/// Last path component without signature extensions, ignoring trailing slashes.
+/// Other dots are kept, so versioned accessions like `GCF_000195915.1` survive.
+fn file_name(location: &str) -> &str {
+    let location = location.trim_end_matches('/');
+    let file_name = location.rsplit('/').next().unwrap_or(location);
+    SIG_EXTENSIONS
+        .iter()
+        .find_map(|ext| file_name.strip_suffix(ext))
+        .filter(|stem| !stem.is_empty())
+        .unwrap_or(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_empty_name() {
+        let names = NameResolver::default();
+        assert_eq!(names.resolve(""), "");
+    }
+
+    #[test]
+    fn resolve_url() {
+        let names = NameResolver::default();
+        assert_eq!(
+            names.resolve("https://example.org/sigs/SRR123456.sig.gz"),
+            "SRR123456"
+        );
+    }
+
+    #[test]
+    fn resolve_trailing_slash() {
+        let names = NameResolver::default();
+        assert_eq!(names.resolve("sigs/SRR123456/"), "SRR123456");
+        assert_eq!(names.resolve("/"), "");
+    }
+
+    #[test]
+    fn resolve_versioned_accession() {
+        let names = NameResolver::default();
+        assert_eq!(
+            names.resolve("genomes/GCF_000195915.1.sig"),
+            "GCF_000195915.1"
+        );
+    }
+
+    #[test]
+    fn resolve_pattern() {
+        let names =
+            NameResolver::new(NameSource::Name, Some(r"^(?P<accession>GC[AF]_\d+\.\d+)")).unwrap();
+        assert_eq!(
+            names.resolve("GCF_000195915.1 Helicobacter pylori"),
+            "GCF_000195915.1"
+        );
+
+        let names = NameResolver::new(NameSource::Name, Some(r"run (\w+)")).unwrap();
+        assert_eq!(names.resolve("run SRR123456"), "SRR123456");
+    }
+
+    #[test]
+    fn resolve_pattern_not_matching() {
+        let names = NameResolver::new(NameSource::Name, Some(r"^SRR\d+$")).unwrap();
+        assert_eq!(
+            names.resolve("GCF_000195915.1 Helicobacter pylori"),
+            "GCF_000195915.1 Helicobacter pylori"
+        );
+    }
+
+    #[test]
+    fn resolve_without_location() {
+        // No manifest, or a name missing from it, falls back to the signature name
+        let names = NameResolver::new(NameSource::Location, None).unwrap();
+        assert!(!names.has_locations());
+        assert_eq!(names.resolve("sigs/SRR123456.sig"), "sigs/SRR123456.sig");
+    }
+
+    #[test]
+    fn resolve_with_manifest() {
+        let manifest = Manifest::from_reader(
+            "internal_location,md5,md5short,ksize,moltype,num,scaled,n_hashes,with_abundance,name,filename
+sigs/SRR123456.sig.gz,0123456789abcdef,01234567,31,DNA,0,1000,10,0,reads/SRR654321.fastq.gz,-
+"
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let names = NameResolver::default().with_manifest(&manifest);
+        assert!(names.has_locations());
+        assert_eq!(
+            names.resolve("reads/SRR654321.fastq.gz"),
+            "SRR654321.fastq.gz"
+        );
+
+        let names = NameResolver::new(NameSource::Location, None)
+            .unwrap()
+            .with_manifest(&manifest);
+        assert_eq!(
+            names.resolve("reads/SRR654321.fastq.gz"),
+            "sigs/SRR123456.sig.gz"
+        );
+    }
+
+    #[test]
+    fn name_source() {
+        assert_eq!(NameSource::default(), NameSource::Filename);
+        assert_eq!("location".parse(), Ok(NameSource::Location));
+        assert!("accession".parse::<NameSource>().is_err());
+    }
+}
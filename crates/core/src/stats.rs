@@ -0,0 +1,369 @@
+//! Similarity statistics for search matches.
+//!
+//! The RevIndex only reports how many hashes each dataset shares with the query.
+//! Statistics depending on the dataset size need the manifest used to build the index.
+
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+
+use serde::Deserialize;
+use sourmash::encodings::Idx;
+use sourmash::index::revindex::{HashToColor, RevIndex, RevIndexOps};
+use sourmash::signature::{Signature, SigsTrait};
+use sourmash::sketch::minhash::KmerMinHash;
+use sourmash::sketch::Sketch;
+
+/// Number of hashes in each dataset of an index, by signature name.
+#[derive(Debug, Default)]
+pub struct DatasetSizes {
+    sizes: HashMap<String, usize>,
+}
+
+/// Manifest columns needed for dataset sizes
+#[derive(Deserialize)]
+struct SizeRecord {
+    name: String,
+    ksize: u32,
+    scaled: u64,
+    n_hashes: usize,
+}
+
+impl DatasetSizes {
+    /// Read dataset sizes from a manifest CSV, for sketches with `ksize`.
+    ///
+    /// Sizes of sketches with a smaller scaled are adjusted to the index `scaled`,
+    /// as the index only keeps the hashes under its max_hash.
+    pub fn from_reader<R: Read>(rdr: R, ksize: u32, scaled: u64) -> Result<Self, csv::Error> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .comment(Some(b'#'))
+            .from_reader(rdr);
+
+        let mut sizes = HashMap::new();
+        for record in rdr.deserialize() {
+            let record: SizeRecord = record?;
+            if record.ksize != ksize || record.scaled == 0 || record.scaled > scaled {
+                continue;
+            }
+            let size = (record.n_hashes as u128 * record.scaled as u128 / scaled as u128) as usize;
+            sizes.entry(record.name).or_insert(size);
+        }
+
+        Ok(DatasetSizes { sizes })
+    }
+
+    pub fn get(&self, name: &str) -> Option<usize> {
+        self.sizes.get(name).copied()
+    }
+}
+
+/// Similarity between a query and a matched dataset, from the number of shared hashes.
+#[derive(Debug, Clone, Copy)]
+pub struct MatchStats {
+    /// Fraction of the query contained in the dataset
+    pub containment: f64,
+    /// ANI estimated from `containment`
+    pub containment_ani: f64,
+    /// Containment of the smaller of query and dataset in the other
+    pub max_containment: Option<f64>,
+    /// ANI estimated from `max_containment`
+    pub max_containment_ani: Option<f64>,
+    pub jaccard: Option<f64>,
+}
+
+impl MatchStats {
+    /// `match_size` is only known if the index has a manifest with dataset sizes.
+    pub fn new(
+        intersect: usize,
+        query_size: usize,
+        match_size: Option<usize>,
+        ksize: usize,
+    ) -> Self {
+        let containment = ratio(intersect, query_size);
+        let max_containment =
+            match_size.map(|size| ratio(intersect, query_size.min(size).max(intersect)));
+        let jaccard = match_size.map(|size| {
+            let union = (query_size + size).saturating_sub(intersect).max(intersect);
+            ratio(intersect, union)
+        });
+
+        MatchStats {
+            containment,
+            containment_ani: ani_from_containment(containment, ksize),
+            max_containment,
+            max_containment_ani: max_containment.map(|c| ani_from_containment(c, ksize)),
+            jaccard,
+        }
+    }
+}
+
+fn ratio(part: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 / total as f64
+    }
+}
+
+/// Point estimate of the average nucleotide identity from a containment,
+/// assuming mutations are independent: ANI = C^(1/k).
+pub fn ani_from_containment(containment: f64, ksize: usize) -> f64 {
+    if containment <= 0.0 || ksize == 0 {
+        0.0
+    } else {
+        containment.min(1.0).powf(1.0 / ksize as f64)
+    }
+}
+
+/// Fraction of the query abundance contained in each matched signature, by name.
+///
+/// The matches are the signatures of the datasets passing the search threshold,
+/// and each one is intersected with the query abundances in a single pass.
+/// Returns an empty map if the query doesn't track abundances.
+pub fn weighted_containment(query: &KmerMinHash, matches: &[Signature]) -> HashMap<String, f64> {
+    if !query.track_abundance() {
+        return HashMap::new();
+    }
+
+    let abunds: HashMap<u64, u64> = query.to_vec_abunds().into_iter().collect();
+    let total: u64 = abunds.values().sum();
+    if total == 0 {
+        return HashMap::new();
+    }
+
+    matches
+        .iter()
+        .map(|sig| {
+            let weight: u64 = match_hashes(sig, query.ksize())
+                .iter()
+                .filter_map(|hash| abunds.get(hash))
+                .sum();
+            (sig.name(), weight as f64 / total as f64)
+        })
+        .collect()
+}
+
+/// Weighted containment of `query` in the `matched` datasets of an index,
+/// given as `(dataset id, shared hashes)` pairs from a search counter.
+///
+/// Each matched signature is loaded from the index, so callers should pass only
+/// the datasets they report. Returns an empty map if the query doesn't track abundances.
+pub fn weighted_containment_for(
+    db: &RevIndex,
+    matched: impl IntoIterator<Item = (Idx, usize)>,
+    query: &KmerMinHash,
+) -> Result<HashMap<String, f64>, sourmash::Error> {
+    let matched: Vec<_> = matched.into_iter().collect();
+    if !query.track_abundance() || matched.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    // The index collection is not public, so signatures are loaded the same way
+    // gather loads its matches. Without hash colors no hashes are removed
+    // between matches, so each dataset is loaded once.
+    let sigs: Vec<_> = db
+        .gather(
+            matched.into_iter().collect(),
+            Default::default(),
+            HashToColor::from_iter(std::iter::empty()),
+            0,
+            query,
+            None,
+        )?
+        .iter()
+        .map(|m| m.get_match())
+        .collect();
+    Ok(weighted_containment(query, &sigs))
+}
+
+/// Abundance-weighted statistics for the query hashes assigned to a gather match.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GatherAbundance {
+    /// Fraction of the query abundance assigned to this match
+    pub f_unique_weighted: f64,
+    pub average_abund: f64,
+    pub median_abund: f64,
+    pub std_abund: f64,
+}
+
+/// Abundance statistics for each gather match, in the same order.
+///
+/// Each query hash is assigned to the first match containing it.
+/// Returns an empty list if the query doesn't track abundances.
+pub fn gather_abundances(query: &KmerMinHash, matches: &[Signature]) -> Vec<GatherAbundance> {
+    if !query.track_abundance() {
+        return vec![];
+    }
+
+    let mut remaining: HashMap<u64, u64> = query.to_vec_abunds().into_iter().collect();
+    let total: u64 = remaining.values().sum();
+
+    matches
+        .iter()
+        .map(|sig| {
+            let hashes = match_hashes(sig, query.ksize());
+            let mut abunds: Vec<u64> = hashes
+                .iter()
+                .filter_map(|hash| remaining.remove(hash))
+                .collect();
+            abundance_stats(&mut abunds, total)
+        })
+        .collect()
+}
+
+/// Hashes in the sketch of a match with the same ksize as the query.
+fn match_hashes(sig: &Signature, ksize: usize) -> HashSet<u64> {
+    sig.sketches()
+        .into_iter()
+        .find_map(|sketch| match sketch {
+            Sketch::MinHash(mh) if mh.ksize() == ksize => Some(mh.mins().into_iter().collect()),
+            _ => None,
+        })
+        .unwrap_or_default()
+}
+
+fn abundance_stats(abunds: &mut [u64], total: u64) -> GatherAbundance {
+    if abunds.is_empty() || total == 0 {
+        return GatherAbundance::default();
+    }
+    abunds.sort_unstable();
+
+    let n = abunds.len() as f64;
+    let sum: u64 = abunds.iter().sum();
+    let average_abund = sum as f64 / n;
+    let mid = abunds.len() / 2;
+    let median_abund = if abunds.len() % 2 == 0 {
+        (abunds[mid - 1] + abunds[mid]) as f64 / 2.0
+    } else {
+        abunds[mid] as f64
+    };
+    let variance = abunds
+        .iter()
+        .map(|&a| (a as f64 - average_abund).powi(2))
+        .sum::<f64>()
+        / n;
+
+    GatherAbundance {
+        f_unique_weighted: sum as f64 / total as f64,
+        average_abund,
+        median_abund,
+        std_abund: variance.sqrt(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sourmash::encodings::HashFunctions;
+
+    use super::*;
+
+    fn sketch(hashes: &[(u64, u64)], track_abundance: bool) -> KmerMinHash {
+        let mut mh = KmerMinHash::new(1, 21, HashFunctions::Murmur64Dna, 42, track_abundance, 0);
+        for &(hash, abund) in hashes {
+            mh.add_hash_with_abundance(hash, abund);
+        }
+        mh
+    }
+
+    fn signature(name: &str, hashes: &[u64]) -> Signature {
+        let hashes: Vec<_> = hashes.iter().map(|&h| (h, 1)).collect();
+        Signature::builder()
+            .name(Some(name.into()))
+            .signatures(vec![Sketch::MinHash(sketch(&hashes, false))])
+            .hash_function("DNA")
+            .build()
+    }
+
+    #[test]
+    fn match_stats_without_size() {
+        let stats = MatchStats::new(50, 100, None, 21);
+        assert_eq!(stats.containment, 0.5);
+        assert_eq!(stats.containment_ani, 0.5f64.powf(1.0 / 21.0));
+        assert_eq!(stats.max_containment, None);
+        assert_eq!(stats.max_containment_ani, None);
+        assert_eq!(stats.jaccard, None);
+    }
+
+    #[test]
+    fn match_stats_with_size() {
+        // The dataset is smaller than the query, and half of it is shared
+        let stats = MatchStats::new(20, 100, Some(40), 21);
+        assert_eq!(stats.containment, 0.2);
+        assert_eq!(stats.max_containment, Some(0.5));
+        assert_eq!(stats.jaccard, Some(20.0 / 120.0));
+    }
+
+    #[test]
+    fn match_stats_inconsistent_size() {
+        // Sizes from a manifest built with other parameters can be under the intersection
+        let stats = MatchStats::new(20, 100, Some(10), 21);
+        assert_eq!(stats.max_containment, Some(1.0));
+        assert_eq!(stats.max_containment_ani, Some(1.0));
+        assert!(stats.jaccard.unwrap() <= 1.0);
+    }
+
+    #[test]
+    fn match_stats_empty_query() {
+        let stats = MatchStats::new(0, 0, Some(0), 21);
+        assert_eq!(stats.containment, 0.0);
+        assert_eq!(stats.containment_ani, 0.0);
+        assert_eq!(stats.jaccard, Some(0.0));
+    }
+
+    #[test]
+    fn abundance_stats_odd() {
+        let stats = abundance_stats(&mut [5, 1, 3], 18);
+        assert_eq!(stats.f_unique_weighted, 0.5);
+        assert_eq!(stats.average_abund, 3.0);
+        assert_eq!(stats.median_abund, 3.0);
+        assert_eq!(stats.std_abund, (8.0f64 / 3.0).sqrt());
+    }
+
+    #[test]
+    fn abundance_stats_even() {
+        let stats = abundance_stats(&mut [4, 1, 2, 1], 8);
+        assert_eq!(stats.f_unique_weighted, 1.0);
+        assert_eq!(stats.average_abund, 2.0);
+        assert_eq!(stats.median_abund, 1.5);
+    }
+
+    #[test]
+    fn abundance_stats_empty() {
+        let stats = abundance_stats(&mut [], 10);
+        assert_eq!(stats.f_unique_weighted, 0.0);
+        assert_eq!(stats.median_abund, 0.0);
+
+        let stats = abundance_stats(&mut [1, 2], 0);
+        assert_eq!(stats.average_abund, 0.0);
+    }
+
+    #[test]
+    fn gather_abundances_assigns_hashes_once() {
+        let query = sketch(&[(1, 4), (2, 2), (3, 2)], true);
+        let matches = [signature("a", &[1, 2]), signature("b", &[2, 3])];
+
+        let stats = gather_abundances(&query, &matches);
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].f_unique_weighted, 0.75);
+        assert_eq!(stats[0].median_abund, 3.0);
+        assert_eq!(stats[1].f_unique_weighted, 0.25);
+        assert_eq!(stats[1].average_abund, 2.0);
+    }
+
+    #[test]
+    fn weighted_containment_by_abundance() {
+        let query = sketch(&[(1, 10), (2, 5), (3, 5)], true);
+        let matches = [signature("a", &[1, 4]), signature("b", &[2, 3])];
+
+        let weighted = weighted_containment(&query, &matches);
+        assert_eq!(weighted["a"], 0.5);
+        assert_eq!(weighted["b"], 0.5);
+    }
+
+    #[test]
+    fn weighted_containment_without_abundance() {
+        let query = sketch(&[(1, 1), (2, 1)], false);
+        let matches = [signature("a", &[1, 2])];
+
+        assert!(weighted_containment(&query, &matches).is_empty());
+    }
+}
@@ -1,7 +1,9 @@
 use camino::Utf8Path as Path;
 use camino::Utf8PathBuf as PathBuf;
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use log::info;
+use mastiff_core::names::{NameResolver, NameSource};
+use mastiff_core::stats::{gather_abundances, weighted_containment_for, DatasetSizes, MatchStats};
 
 use sourmash::collection::Collection;
 use sourmash::index::revindex::{prepare_query, RevIndex, RevIndexOps};
@@ -17,6 +19,53 @@ struct Cli {
     command: Commands,
 }
 
+/// How to report the datasets matched in the index.
+#[derive(Args, Debug)]
+struct NameArgs {
+    /// Where accessions come from: name, filename or location (needs --manifest)
+    #[clap(long = "names", default_value = "filename")]
+    names: NameSource,
+
+    /// Regex extracting the accession, from the `accession` group or the first one
+    #[clap(long = "name_pattern")]
+    name_pattern: Option<String>,
+
+    /// Manifest for the signatures in the index, with their locations and sizes
+    #[clap(short = 'm', long = "manifest")]
+    manifest: Option<PathBuf>,
+}
+
+impl NameArgs {
+    fn resolver(&self) -> Result<NameResolver, Box<dyn std::error::Error>> {
+        let names = NameResolver::new(self.names, self.name_pattern.as_deref())?;
+        match &self.manifest {
+            Some(path) => {
+                let rdr = std::fs::File::open(path)?;
+                Ok(names.with_manifest(&Manifest::from_reader(rdr)?))
+            }
+            None if self.names == NameSource::Location => {
+                Err("--names location needs a --manifest".into())
+            }
+            None => Ok(names),
+        }
+    }
+
+    /// Dataset sizes from the manifest, if there is one.
+    fn sizes(
+        &self,
+        ksize: u32,
+        scaled: u64,
+    ) -> Result<Option<DatasetSizes>, Box<dyn std::error::Error>> {
+        match &self.manifest {
+            Some(path) => {
+                let rdr = std::fs::File::open(path)?;
+                Ok(Some(DatasetSizes::from_reader(rdr, ksize, scaled)?))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
 #[derive(Subcommand, Debug)]
 enum Commands {
     Index {
@@ -132,6 +181,59 @@ enum Commands {
         #[clap(short = 'c', long = "containment", default_value = "0.2")]
         containment: f64,
 
+        #[clap(flatten)]
+        names: NameArgs,
+
+        /// The path for output
+        #[clap(short = 'o', long = "output")]
+        output: Option<PathBuf>,
+    },
+    /// List the datasets containing each hash
+    Lookup {
+        /// Path to rocksdb index dir
+        index: PathBuf,
+
+        /// Hashes to look up
+        #[clap(required = true)]
+        hashes: Vec<u64>,
+
+        /// ksize
+        #[clap(short = 'k', long = "ksize", default_value = "31")]
+        ksize: u8,
+
+        /// scaled
+        #[clap(short = 's', long = "scaled", default_value = "1000")]
+        scaled: usize,
+
+        #[clap(flatten)]
+        names: NameArgs,
+
+        /// The path for output
+        #[clap(short = 'o', long = "output")]
+        output: Option<PathBuf>,
+    },
+    /// Report the hashes a query shares with a dataset in the index
+    Explain {
+        /// Query signature
+        query_path: PathBuf,
+
+        /// Path to rocksdb index dir
+        index: PathBuf,
+
+        /// Dataset to compare with, by name or accession
+        dataset: String,
+
+        /// ksize
+        #[clap(short = 'k', long = "ksize", default_value = "31")]
+        ksize: u8,
+
+        /// scaled
+        #[clap(short = 's', long = "scaled", default_value = "1000")]
+        scaled: usize,
+
+        #[clap(flatten)]
+        names: NameArgs,
+
         /// The path for output
         #[clap(short = 'o', long = "output")]
         output: Option<PathBuf>,
@@ -198,13 +300,25 @@ fn gather<P: AsRef<Path>>(
     )?;
 
     info!("matches: {}", matches.len());
-    for match_ in matches {
-        println!(
-            "{} {} {}",
-            match_.name(),
-            match_.intersect_bp(),
-            match_.f_match()
-        )
+    let sigs: Vec<_> = matches.iter().map(|m| m.get_match()).collect();
+    let abundances = gather_abundances(&query, &sigs);
+    for (rank, match_) in matches.iter().enumerate() {
+        match abundances.get(rank) {
+            Some(abund) => println!(
+                "{} {} {} {} {}",
+                match_.name(),
+                match_.intersect_bp(),
+                match_.f_match(),
+                abund.f_unique_weighted,
+                abund.median_abund
+            ),
+            None => println!(
+                "{} {} {}",
+                match_.name(),
+                match_.intersect_bp(),
+                match_.f_match()
+            ),
+        }
     }
 
     Ok(())
@@ -216,6 +330,7 @@ fn search<P: AsRef<Path>>(
     selection: Selection,
     threshold_bp: usize,
     minimum_containment: f64,
+    names: &NameArgs,
     _output: Option<P>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let query_sig = Signature::from_path(queries_file.as_ref())?
@@ -227,10 +342,13 @@ fn search<P: AsRef<Path>>(
         query = Some(q);
     }
     let query = query.expect("Couldn't find a compatible MinHash");
-    let query_size = query.size() as f64;
+    let query_size = query.size();
 
     let threshold = threshold_bp / query.scaled() as usize;
 
+    let sizes = names.sizes(query.ksize() as u32, query.scaled())?;
+    let names = names.resolver()?;
+
     let db = RevIndex::open(index.as_ref(), true)?;
     info!("Loaded DB");
 
@@ -238,26 +356,137 @@ fn search<P: AsRef<Path>>(
     let counter = db.counter_for_query(&query);
     info!("Counter built");
 
-    let matches = db.matches_from_counter(counter, threshold);
-
-    //info!("matches: {}", matches.len());
-    println!("SRA ID,containment");
-    matches
+    // Only the reported datasets are looked up in the index
+    let matched: Vec<_> = counter
+        .most_common()
         .into_iter()
-        .filter_map(|(path, size)| {
-            let containment = size as f64 / query_size;
-            if containment >= minimum_containment {
-                println!(
-                    "{},{}",
-                    path.split("/").last().unwrap().split(".").next().unwrap(),
-                    containment
-                );
-                Some(())
-            } else {
-                None
-            }
+        .filter(|&(_, size)| {
+            size >= threshold && size as f64 / query_size as f64 >= minimum_containment
         })
-        .count();
+        .collect();
+
+    let matches = db.matches_from_counter(matched.iter().copied().collect(), threshold);
+
+    let weighted = if query.track_abundance() {
+        info!("Calculating abundance-weighted containment");
+        Some(weighted_containment_for(&db, matched, &query)?)
+    } else {
+        None
+    };
+
+    let optional = |v: Option<f64>| v.map(|v| v.to_string()).unwrap_or_default();
+
+    //info!("matches: {}", matches.len());
+    let mut wtr = csv::Writer::from_writer(std::io::stdout());
+    wtr.write_record([
+        "SRA ID",
+        "containment",
+        "containment_ani",
+        "max_containment",
+        "max_containment_ani",
+        "jaccard",
+        "weighted_containment",
+    ])?;
+    for (name, size) in matches {
+        let match_size = sizes.as_ref().and_then(|s| s.get(&name));
+        let stats = MatchStats::new(size, query_size, match_size, query.ksize());
+
+        let weighted = weighted
+            .as_ref()
+            .map(|w| w.get(&name).copied().unwrap_or_default());
+        wtr.write_record(&[
+            names.resolve(&name).to_string(),
+            stats.containment.to_string(),
+            stats.containment_ani.to_string(),
+            optional(stats.max_containment),
+            optional(stats.max_containment_ani),
+            optional(stats.jaccard),
+            optional(weighted),
+        ])?;
+    }
+    wtr.flush()?;
+
+    Ok(())
+}
+
+fn lookup<P: AsRef<Path>>(
+    index: P,
+    hashes: &[u64],
+    ksize: u8,
+    scaled: usize,
+    names: &NameResolver,
+    output: Option<P>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use sourmash::sketch::minhash::{max_hash_for_scaled, KmerMinHash};
+    use std::fs::File;
+    use std::io::BufWriter;
+
+    let template = KmerMinHash::builder()
+        .num(0)
+        .max_hash(max_hash_for_scaled(scaled as u64))
+        .ksize(ksize as u32)
+        .build();
+
+    let db = RevIndex::open(index.as_ref(), true)?;
+    info!("Loaded DB");
+
+    let datasets = mastiff_core::lookup::lookup(&db, &template, hashes, names)?;
+
+    let out: Box<dyn std::io::Write> = match output {
+        Some(path) => Box::new(BufWriter::new(File::create(path.as_ref())?)),
+        None => Box::new(std::io::stdout()),
+    };
+    let mut wtr = csv::Writer::from_writer(out);
+    wtr.write_record(["hash", "dataset"])?;
+    for (hash, accessions) in datasets {
+        info!("{hash}: {} datasets", accessions.len());
+        for accession in accessions {
+            wtr.write_record(&[hash.to_string(), accession])?;
+        }
+    }
+    wtr.flush()?;
+
+    Ok(())
+}
+
+fn explain<P: AsRef<Path>>(
+    queries_file: P,
+    index: P,
+    dataset: &str,
+    selection: Selection,
+    names: &NameResolver,
+    output: Option<P>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use std::fs::File;
+    use std::io::{BufWriter, Write};
+
+    let query_sig = Signature::from_path(queries_file.as_ref())?
+        .swap_remove(0)
+        .select(&selection)?;
+
+    let mut query = None;
+    if let Some(q) = prepare_query(query_sig, &selection) {
+        query = Some(q);
+    }
+    let query = query.expect("Couldn't find a compatible MinHash");
+
+    let db = RevIndex::open(index.as_ref(), true)?;
+    info!("Loaded DB");
+
+    let explanation = mastiff_core::explain::explain(&db, &query, dataset, names)?;
+    info!(
+        "{} hashes shared with {}",
+        explanation.hashes.len(),
+        explanation.dataset
+    );
+
+    let mut out: Box<dyn Write> = match output {
+        Some(path) => Box::new(BufWriter::new(File::create(path.as_ref())?)),
+        None => Box::new(std::io::stdout()),
+    };
+    for hash in explanation.hashes {
+        writeln!(out, "{hash}")?;
+    }
 
     Ok(())
 }
@@ -466,11 +695,18 @@ fn repair<P: AsRef<Path>>(output: P, colors: bool) {
 */
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
     use Commands::*;
 
     let opts = Cli::parse();
 
+    // explain and lookup do many small queries, each logged by sourmash
+    let default_filter = match opts.command {
+        Explain { .. } | Lookup { .. } => "info,sourmash=warn",
+        _ => "info",
+    };
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(default_filter))
+        .init();
+
     match opts.command {
         Index {
             output,
@@ -521,6 +757,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             ksize,
             scaled,
             containment,
+            names,
         } => {
             let selection = Selection::builder()
                 .ksize(ksize.into())
@@ -533,6 +770,38 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 selection,
                 threshold_bp,
                 containment,
+                &names,
+                output,
+            )?
+        }
+        Lookup {
+            index,
+            hashes,
+            ksize,
+            scaled,
+            names,
+            output,
+        } => lookup(index, &hashes, ksize, scaled, &names.resolver()?, output)?,
+        Explain {
+            query_path,
+            index,
+            dataset,
+            ksize,
+            scaled,
+            names,
+            output,
+        } => {
+            let selection = Selection::builder()
+                .ksize(ksize.into())
+                .scaled(scaled as u32)
+                .build();
+
+            explain(
+                query_path,
+                index,
+                &dataset,
+                selection,
+                &names.resolver()?,
                 output,
             )?
         }
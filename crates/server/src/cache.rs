@@ -0,0 +1,336 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use sourmash::sketch::minhash::KmerMinHash;
+use tower::BoxError;
+
+use crate::config;
+use crate::index::{SearchOptions, SearchResults};
+
+/// How often expired results are removed from the cache dir
+const CLEANUP_PERIOD: Duration = Duration::from_secs(3600);
+
+/// Cache for search results, keyed by query md5 (and abundances, if tracked),
+/// index (and when it was built) and search options.
+///
+/// Results are kept in memory as JSON, evicting the least recently used
+/// once over `max_bytes`. If a directory is configured results are also
+/// written there, so they survive evictions and restarts.
+/// Files older than `dir_ttl` are ignored, and removed by a periodic task.
+pub struct ResultCache {
+    entries: Mutex<Lru>,
+    max_bytes: usize,
+    dir: Option<PathBuf>,
+    /// Kept forever if zero
+    dir_ttl: Duration,
+}
+
+#[derive(Default)]
+struct Lru {
+    /// Serialized results and the last time they were used
+    entries: HashMap<String, (String, u64)>,
+    /// Keys by last use, oldest first
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    bytes: usize,
+}
+
+impl Lru {
+    fn get(&mut self, key: &str) -> Option<String> {
+        self.tick += 1;
+        let (value, used) = self.entries.get_mut(key)?;
+        self.order.remove(used);
+        *used = self.tick;
+        self.order.insert(self.tick, key.into());
+        Some(value.clone())
+    }
+
+    fn insert(&mut self, key: String, value: String, max_bytes: usize) {
+        let size = key.len() + value.len();
+        if size > max_bytes {
+            return;
+        }
+        self.remove(&key);
+
+        while self.bytes + size > max_bytes {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some((value, _)) = self.entries.remove(&oldest) {
+                self.bytes -= oldest.len() + value.len();
+            }
+        }
+
+        self.tick += 1;
+        self.bytes += size;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, self.tick));
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some((value, used)) = self.entries.remove(key) {
+            self.order.remove(&used);
+            self.bytes -= key.len() + value.len();
+        }
+    }
+}
+
+impl ResultCache {
+    /// Create a cache, or `None` if it is disabled in the config.
+    pub fn new(config: &config::Cache) -> Result<Option<Self>, BoxError> {
+        if config.max_bytes == 0 {
+            return Ok(None);
+        }
+
+        if let Some(dir) = &config.dir {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| format!("Error creating cache dir {}: {e}", dir.display()))?;
+        }
+
+        Ok(Some(ResultCache {
+            entries: Default::default(),
+            max_bytes: config.max_bytes,
+            dir: config.dir.clone(),
+            dir_ttl: Duration::from_secs(config.dir_ttl_secs),
+        }))
+    }
+
+    /// `built_at` keeps results from a previous build of the index from being used.
+    /// The query md5 only covers its hashes, so a digest of the abundances is added
+    /// for queries tracking them, as they change the weighted containment.
+    pub fn key(
+        index: &str,
+        built_at: Option<u64>,
+        query: &KmerMinHash,
+        options: &SearchOptions,
+    ) -> String {
+        let abundances = match query.abunds() {
+            Some(abunds) => {
+                let bytes: Vec<u8> = abunds.iter().flat_map(|a| a.to_le_bytes()).collect();
+                format!("-abund{:016x}", sourmash::_hash_murmur(&bytes, 42))
+            }
+            None => String::new(),
+        };
+        format!(
+            "{index}-{}-{}{abundances}-{}-{}-{}",
+            built_at.unwrap_or_default(),
+            query.md5sum(),
+            options.threshold,
+            options.min_containment,
+            options.max_results
+        )
+    }
+
+    pub async fn get(&self, key: &str) -> Option<SearchResults> {
+        let mut value = self.entries.lock().unwrap().get(key);
+
+        if value.is_none() {
+            let path = self.path(key)?;
+            let data = self.read_file(&path).await?;
+            self.entries
+                .lock()
+                .unwrap()
+                .insert(key.into(), data.clone(), self.max_bytes);
+            value = Some(data);
+        }
+
+        match serde_json::from_str(&value?) {
+            Ok(results) => Some(results),
+            Err(e) => {
+                tracing::warn!("Error reading cached results for {key}: {e}");
+                None
+            }
+        }
+    }
+
+    pub async fn insert(&self, key: String, results: &SearchResults) {
+        let value = match serde_json::to_string(results) {
+            Ok(value) => value,
+            Err(e) => {
+                tracing::warn!("Error caching results for {key}: {e}");
+                return;
+            }
+        };
+
+        if let Some(path) = self.path(&key) {
+            if let Err(e) = tokio::fs::write(&path, &value).await {
+                tracing::warn!("Error writing cached results to {}: {e}", path.display());
+            }
+        }
+
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key, value, self.max_bytes);
+    }
+
+    /// Remove expired results from the cache dir periodically.
+    /// Must be called within the runtime.
+    pub fn spawn_cleanup(self: &Arc<Self>) {
+        let Some(dir) = self.dir.clone() else {
+            return;
+        };
+        if self.dir_ttl.is_zero() {
+            return;
+        }
+
+        let ttl = self.dir_ttl;
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CLEANUP_PERIOD);
+            loop {
+                interval.tick().await;
+                let dir = dir.clone();
+                match tokio::task::spawn_blocking(move || remove_expired(&dir, ttl)).await {
+                    Ok(Ok(0)) => {}
+                    Ok(Ok(removed)) => tracing::debug!("Removed {removed} expired cached results"),
+                    Ok(Err(e)) => tracing::warn!("Error removing expired cached results: {e}"),
+                    Err(e) => tracing::warn!("Error removing expired cached results: {e}"),
+                }
+            }
+        });
+    }
+
+    /// Read a result from disk, unless it expired.
+    async fn read_file(&self, path: &Path) -> Option<String> {
+        let modified = tokio::fs::metadata(path).await.ok()?.modified().ok()?;
+        if expired(modified, self.dir_ttl) {
+            return None;
+        }
+        tokio::fs::read_to_string(path).await.ok()
+    }
+
+    /// Location for a cached result on disk, if persistence is enabled.
+    fn path(&self, key: &str) -> Option<PathBuf> {
+        let name: String = key
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        self.dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{name}.json")))
+    }
+}
+
+fn expired(modified: SystemTime, ttl: Duration) -> bool {
+    !ttl.is_zero() && modified.elapsed().is_ok_and(|age| age > ttl)
+}
+
+/// Remove cached results older than `ttl` from `dir`, returning how many were removed.
+fn remove_expired(dir: &Path, ttl: Duration) -> std::io::Result<usize> {
+    let mut removed = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension() != Some("json".as_ref()) {
+            continue;
+        }
+        if expired(std::fs::metadata(&path)?.modified()?, ttl) {
+            std::fs::remove_file(&path)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use sourmash::encodings::HashFunctions;
+
+    use super::*;
+
+    #[test]
+    fn lru_get() {
+        let mut lru = Lru::default();
+        lru.insert("a".into(), "1".into(), 100);
+
+        assert_eq!(lru.get("a").as_deref(), Some("1"));
+        assert_eq!(lru.get("b"), None);
+    }
+
+    #[test]
+    fn lru_evicts_least_recently_used() {
+        // Each entry is 2 bytes, key and value
+        let mut lru = Lru::default();
+        lru.insert("a".into(), "1".into(), 6);
+        lru.insert("b".into(), "2".into(), 6);
+        lru.insert("c".into(), "3".into(), 6);
+        lru.get("a");
+        lru.insert("d".into(), "4".into(), 6);
+
+        assert_eq!(lru.get("b"), None);
+        assert_eq!(lru.get("a").as_deref(), Some("1"));
+        assert_eq!(lru.get("c").as_deref(), Some("3"));
+        assert_eq!(lru.get("d").as_deref(), Some("4"));
+        assert_eq!(lru.bytes, 6);
+    }
+
+    #[test]
+    fn lru_replace() {
+        let mut lru = Lru::default();
+        lru.insert("a".into(), "1".into(), 100);
+        lru.insert("a".into(), "123".into(), 100);
+
+        assert_eq!(lru.get("a").as_deref(), Some("123"));
+        assert_eq!(lru.entries.len(), 1);
+        assert_eq!(lru.order.len(), 1);
+        assert_eq!(lru.bytes, 4);
+    }
+
+    #[test]
+    fn lru_skips_values_over_limit() {
+        let mut lru = Lru::default();
+        lru.insert("a".into(), "1".into(), 4);
+        lru.insert("b".into(), "12345".into(), 4);
+
+        assert_eq!(lru.get("b"), None);
+        assert_eq!(lru.get("a").as_deref(), Some("1"));
+        assert_eq!(lru.bytes, 2);
+    }
+
+    #[test]
+    fn key_abundances() {
+        let options = SearchOptions {
+            threshold: 50,
+            min_containment: 0.0,
+            max_results: 100,
+            columns: vec![],
+        };
+        let sketch = |track_abundance, abund| {
+            let mut mh =
+                KmerMinHash::new(1000, 21, HashFunctions::Murmur64Dna, 42, track_abundance, 0);
+            mh.add_hash_with_abundance(1, abund);
+            mh.add_hash_with_abundance(2, abund);
+            mh
+        };
+
+        let flat = ResultCache::key("sra", Some(1), &sketch(false, 1), &options);
+        let once = ResultCache::key("sra", Some(1), &sketch(true, 1), &options);
+        let twice = ResultCache::key("sra", Some(1), &sketch(true, 2), &options);
+        assert_ne!(flat, once);
+        assert_ne!(once, twice);
+        assert_eq!(
+            twice,
+            ResultCache::key("sra", Some(1), &sketch(true, 2), &options)
+        );
+        assert_ne!(
+            twice,
+            ResultCache::key("sra", Some(2), &sketch(true, 2), &options)
+        );
+    }
+
+    #[test]
+    fn expiry() {
+        let hour_ago = SystemTime::now() - Duration::from_secs(3600);
+
+        assert!(expired(hour_ago, Duration::from_secs(60)));
+        assert!(!expired(hour_ago, Duration::from_secs(7200)));
+        assert!(!expired(hour_ago, Duration::ZERO));
+    }
+}
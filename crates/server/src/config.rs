@@ -0,0 +1,421 @@
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use axum::http::{header, HeaderValue, Method};
+use color_eyre::eyre::{bail, eyre, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::index::IndexSpec;
+
+/// Index names that would be shadowed by other routes, like `/search/hashes`
+const RESERVED_INDEX_NAMES: &[&str] = &["hashes"];
+
+/// Server configuration, usually loaded from a TOML file.
+///
+/// Every section has defaults, so an empty file (or no file at all)
+/// is a valid configuration as long as indexes are passed in the command line.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: IpAddr,
+    pub port: u16,
+    /// Path to static assets
+    pub assets: PathBuf,
+    pub defaults: IndexDefaults,
+    pub indexes: Vec<IndexSpec>,
+    pub limits: Limits,
+    pub timeouts: Timeouts,
+    pub concurrency: Concurrency,
+    pub jobs: Jobs,
+    pub cache: Cache,
+    pub cors: Cors,
+    pub observability: Observability,
+}
+
+/// Settings used by indexes that don't define their own.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct IndexDefaults {
+    pub ksize: u8,
+    pub scaled: usize,
+    pub threshold_bp: usize,
+}
+
+/// Bounds for the parameters clients can set per request.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Lowest threshold_bp a client can request
+    pub min_threshold_bp: usize,
+    /// Maximum number of matches returned per query
+    pub max_results: usize,
+    /// Maximum number of queries in a batch request, or hashes in a lookup
+    pub max_batch_size: usize,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    /// Maximum time to answer a request, in seconds
+    pub request_secs: u64,
+    /// Maximum time for the `/ready` probe query, in seconds
+    pub ready_secs: u64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Concurrency {
+    /// Requests processed at the same time. Others are rejected while at capacity.
+    pub max_requests: usize,
+}
+
+/// Settings for jobs running in the background, submitted to `/jobs`.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Jobs {
+    /// Jobs running at the same time
+    pub workers: usize,
+    /// Jobs waiting to run. New jobs are rejected while the queue is full.
+    pub max_queued: usize,
+    /// Time to keep results after a job finishes, in seconds
+    pub result_ttl_secs: u64,
+}
+
+/// Settings for the search results cache.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Cache {
+    /// Memory used for cached results, in bytes. The cache is disabled if 0.
+    pub max_bytes: usize,
+    /// Directory to persist cached results. Results are only kept in memory if unset.
+    pub dir: Option<PathBuf>,
+    /// Time to keep results in `dir`, in seconds. They are kept forever if 0.
+    pub dir_ttl_secs: u64,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Cors {
+    /// Origins allowed to make cross-origin requests. Use `"*"` for any origin.
+    /// CORS is disabled if empty.
+    pub allowed_origins: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Json,
+    Text,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Observability {
+    /// Log filter, in `RUST_LOG` syntax. `$RUST_LOG` takes precedence.
+    pub log_filter: String,
+    pub log_format: LogFormat,
+    /// Sentry DSN. `$SENTRY_DSN` takes precedence.
+    /// Errors are only reported to logs if unset, or if built without the `sentry` feature.
+    pub sentry_dsn: Option<String>,
+    /// Environment reported to Sentry. `$MASTIFF_ENVIRONMENT` takes precedence.
+    pub environment: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 3059,
+            assets: "assets/".into(),
+            defaults: Default::default(),
+            indexes: vec![],
+            limits: Default::default(),
+            timeouts: Default::default(),
+            concurrency: Default::default(),
+            jobs: Default::default(),
+            cache: Default::default(),
+            cors: Default::default(),
+            observability: Default::default(),
+        }
+    }
+}
+
+impl Default for IndexDefaults {
+    fn default() -> Self {
+        IndexDefaults {
+            ksize: 21,
+            scaled: 1000,
+            threshold_bp: 50000,
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            min_threshold_bp: 10000,
+            max_results: 10000,
+            max_batch_size: 1000,
+        }
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            request_secs: 3600,
+            ready_secs: 5,
+        }
+    }
+}
+
+impl Default for Concurrency {
+    fn default() -> Self {
+        Concurrency { max_requests: 200 }
+    }
+}
+
+impl Default for Jobs {
+    fn default() -> Self {
+        Jobs {
+            workers: 4,
+            max_queued: 100,
+            result_ttl_secs: 3600,
+        }
+    }
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Cache {
+            max_bytes: 100 * 1024 * 1024,
+            dir: None,
+            dir_ttl_secs: 7 * 24 * 3600,
+        }
+    }
+}
+
+impl Default for Observability {
+    fn default() -> Self {
+        Observability {
+            log_filter: "mastiff=debug,tower_http=debug".into(),
+            log_format: LogFormat::Json,
+            sentry_dsn: None,
+            environment: "development".into(),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let data = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Error reading config file {}", path.display()))?;
+        toml::from_str(&data)
+            .wrap_err_with(|| format!("Error parsing config file {}", path.display()))
+    }
+
+    /// Check for values that would only fail later, after the server started.
+    pub fn validate(&self) -> Result<()> {
+        if self.indexes.is_empty() {
+            bail!("No indexes configured");
+        }
+
+        for (i, spec) in self.indexes.iter().enumerate() {
+            if self.indexes[..i]
+                .iter()
+                .any(|other| other.name == spec.name)
+            {
+                bail!("Duplicated index name: {}", spec.name);
+            }
+            if RESERVED_INDEX_NAMES.contains(&spec.name.as_str()) {
+                bail!("Index name '{}' is reserved", spec.name);
+            }
+            if spec.ksize.unwrap_or(self.defaults.ksize) == 0 {
+                bail!("Index '{}': ksize must be positive", spec.name);
+            }
+            if spec.scaled.unwrap_or(self.defaults.scaled) == 0 {
+                bail!("Index '{}': scaled must be positive", spec.name);
+            }
+        }
+
+        if self.defaults.threshold_bp < self.limits.min_threshold_bp {
+            bail!(
+                "defaults.threshold_bp ({}) is below limits.min_threshold_bp ({}), requests using it would be rejected",
+                self.defaults.threshold_bp,
+                self.limits.min_threshold_bp
+            );
+        }
+        if self.limits.max_results == 0 {
+            bail!("limits.max_results must be positive");
+        }
+        if self.limits.max_batch_size == 0 {
+            bail!("limits.max_batch_size must be positive");
+        }
+        if self.timeouts.request_secs == 0 {
+            bail!("timeouts.request_secs must be positive");
+        }
+        if self.timeouts.ready_secs == 0 {
+            bail!("timeouts.ready_secs must be positive");
+        }
+        if self.concurrency.max_requests == 0 {
+            bail!("concurrency.max_requests must be positive");
+        }
+        if self.jobs.workers == 0 {
+            bail!("jobs.workers must be positive");
+        }
+        if self.jobs.max_queued == 0 {
+            bail!("jobs.max_queued must be positive");
+        }
+
+        let _ = self.cors.allow_origin()?;
+
+        Ok(())
+    }
+
+    pub fn addr(&self) -> std::net::SocketAddr {
+        (self.bind, self.port).into()
+    }
+
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.timeouts.request_secs)
+    }
+
+    pub fn ready_timeout(&self) -> Duration {
+        Duration::from_secs(self.timeouts.ready_secs)
+    }
+}
+
+impl Cors {
+    fn allow_origin(&self) -> Result<AllowOrigin> {
+        if self.allowed_origins.iter().any(|o| o == "*") {
+            return Ok(AllowOrigin::any());
+        }
+
+        let origins = self
+            .allowed_origins
+            .iter()
+            .map(|o| HeaderValue::from_str(o).map_err(|_| eyre!("Invalid CORS origin: {o}")))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(AllowOrigin::list(origins))
+    }
+
+    /// Layer for handling CORS requests, if any origins are allowed.
+    pub fn layer(&self) -> Option<CorsLayer> {
+        if self.allowed_origins.is_empty() {
+            return None;
+        }
+
+        let origins = self
+            .allow_origin()
+            .expect("CORS config was already validated");
+        Some(
+            CorsLayer::new()
+                .allow_origin(origins)
+                .allow_methods([Method::GET, Method::POST])
+                .allow_headers([header::CONTENT_TYPE, header::ACCEPT]),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_indexes(specs: &[&str]) -> Config {
+        Config {
+            indexes: specs.iter().map(|s| s.parse().unwrap()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn defaults_from_empty_toml() {
+        let config: Config = toml::from_str("").unwrap();
+        assert_eq!(config.port, 3059);
+        assert_eq!(config.defaults.ksize, 21);
+        assert_eq!(config.defaults.scaled, 1000);
+        assert_eq!(config.defaults.threshold_bp, 50000);
+        assert_eq!(config.limits.min_threshold_bp, 10000);
+        assert_eq!(config.cache.dir, None);
+        assert!(config.indexes.is_empty());
+    }
+
+    #[test]
+    fn partial_sections_keep_defaults() {
+        let config: Config = toml::from_str(
+            r#"
+            port = 8080
+
+            [limits]
+            max_results = 10
+
+            [[indexes]]
+            name = "sra"
+            path = "sra.rocksdb"
+            ksize = 31
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.port, 8080);
+        assert_eq!(config.limits.max_results, 10);
+        assert_eq!(config.limits.max_batch_size, 1000);
+        assert_eq!(config.indexes[0].name, "sra");
+        assert_eq!(config.indexes[0].ksize, Some(31));
+        assert_eq!(config.indexes[0].scaled, None);
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn unknown_fields() {
+        assert!(toml::from_str::<Config>("prot = 8080").is_err());
+        assert!(toml::from_str::<Config>("[limits]\nmax_result = 10").is_err());
+    }
+
+    #[test]
+    fn validate_indexes() {
+        with_indexes(&["sra=sra.rocksdb", "gtdb=gtdb.rocksdb"])
+            .validate()
+            .unwrap();
+
+        assert!(with_indexes(&[]).validate().is_err());
+        assert!(with_indexes(&["sra=sra.rocksdb", "sra=other.rocksdb"])
+            .validate()
+            .is_err());
+        assert!(with_indexes(&["hashes=hashes.rocksdb"]).validate().is_err());
+        assert!(with_indexes(&["sra=sra.rocksdb,ksize=0"])
+            .validate()
+            .is_err());
+
+        let mut config = with_indexes(&["sra=sra.rocksdb"]);
+        config.defaults.scaled = 0;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn validate_default_threshold() {
+        let mut config = with_indexes(&["sra=sra.rocksdb"]);
+        config.defaults.threshold_bp = 10000;
+        config.validate().unwrap();
+
+        config.defaults.threshold_bp = 5000;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn validate_limits() {
+        let mut config = with_indexes(&["sra=sra.rocksdb"]);
+        config.limits.max_results = 0;
+        assert!(config.validate().is_err());
+
+        let mut config = with_indexes(&["sra=sra.rocksdb"]);
+        config.jobs.workers = 0;
+        assert!(config.validate().is_err());
+
+        let mut config = with_indexes(&["sra=sra.rocksdb"]);
+        config.cors.allowed_origins = vec!["bad\norigin".into()];
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,1171 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use mastiff_core::explain::explain;
+use mastiff_core::lookup::lookup;
+use mastiff_core::names::{NameResolver, NameSource};
+use mastiff_core::stats::{gather_abundances, weighted_containment_for, DatasetSizes, MatchStats};
+use serde::{Deserialize, Serialize};
+use sourmash::index::revindex::{RevIndex, RevIndexOps};
+use sourmash::manifest::Manifest;
+use sourmash::signature::{Signature, SigsTrait};
+use sourmash::sketch::minhash::{max_hash_for_scaled, KmerMinHash};
+use sourmash::sketch::Sketch;
+use tokio::sync::Semaphore;
+use tower::BoxError;
+
+use crate::cache::ResultCache;
+use crate::config::IndexDefaults;
+use crate::metadata::Metadata;
+use crate::metrics::Metrics;
+use crate::sketch::{prepare_query, sketch_sequences};
+
+/// Description of an index to be served.
+///
+/// Can be defined in the config file, or in the command line with the format
+/// `[NAME=]PATH[,ksize=K][,scaled=S][,manifest=CSV][,metadata=CSV][,metadata_key=COLUMN]`
+/// `[,names=SOURCE][,name_pattern=REGEX]`. Commas in values must be escaped as `\,`.
+/// If no name is given it is derived from the index directory name,
+/// and ksize/scaled fall back to the configured defaults.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct IndexSpec {
+    pub name: String,
+    pub path: PathBuf,
+    pub ksize: Option<u8>,
+    pub scaled: Option<usize>,
+    /// Manifest for the signatures in the index, as used to build it.
+    /// Needed for statistics depending on the dataset sizes.
+    pub manifest: Option<PathBuf>,
+    /// Where accessions for matches come from: `name`, `filename` or `location`
+    /// (requires a manifest) [default: filename]
+    pub names: Option<NameSource>,
+    /// Regex extracting the accession, from the `accession` group or the first one
+    pub name_pattern: Option<String>,
+    /// CSV with metadata for the datasets, like an SRA runinfo table.
+    pub metadata: Option<PathBuf>,
+    /// Column in the metadata with the dataset accession [default: Run]
+    pub metadata_key: Option<String>,
+    /// Metadata columns to load, all of them if not set.
+    pub metadata_columns: Option<Vec<String>>,
+}
+
+impl FromStr for IndexSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = split_options(s).into_iter();
+        let location = parts.next().unwrap_or_default();
+
+        let (name, path) = match location.split_once('=') {
+            Some((name, path)) => (name.to_string(), PathBuf::from(path)),
+            None => {
+                let path = PathBuf::from(&location);
+                let name = path
+                    .file_stem()
+                    .map(|n| n.to_string_lossy().to_string())
+                    .ok_or_else(|| format!("Can't derive an index name from '{location}'"))?;
+                (name, path)
+            }
+        };
+
+        if name.is_empty() || path.as_os_str().is_empty() {
+            return Err(format!("Invalid index specification '{s}'"));
+        }
+
+        let mut spec = IndexSpec {
+            name,
+            path,
+            ksize: None,
+            scaled: None,
+            manifest: None,
+            names: None,
+            name_pattern: None,
+            metadata: None,
+            metadata_key: None,
+            metadata_columns: None,
+        };
+
+        for option in parts {
+            match option.split_once('=') {
+                Some(("ksize", value)) => {
+                    spec.ksize = Some(value.parse().map_err(|e| format!("Invalid ksize: {e}"))?)
+                }
+                Some(("scaled", value)) => {
+                    spec.scaled = Some(value.parse().map_err(|e| format!("Invalid scaled: {e}"))?)
+                }
+                Some(("manifest", value)) if !value.is_empty() => {
+                    spec.manifest = Some(PathBuf::from(value))
+                }
+                Some(("names", value)) => spec.names = Some(value.parse()?),
+                Some(("name_pattern", value)) if !value.is_empty() => {
+                    spec.name_pattern = Some(value.into())
+                }
+                Some(("metadata", value)) if !value.is_empty() => {
+                    spec.metadata = Some(PathBuf::from(value))
+                }
+                Some(("metadata_key", value)) if !value.is_empty() => {
+                    spec.metadata_key = Some(value.into())
+                }
+                _ => return Err(format!("Unknown index option '{option}'")),
+            }
+        }
+
+        Ok(spec)
+    }
+}
+
+/// Split an index specification on commas, except those escaped as `\,`.
+fn split_options(s: &str) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&',') => {
+                chars.next();
+                parts.last_mut().unwrap().push(',');
+            }
+            ',' => parts.push(String::new()),
+            c => parts.last_mut().unwrap().push(c),
+        }
+    }
+    parts
+}
+
+/// Search parameters after applying defaults and validating against the server limits.
+#[derive(Clone)]
+pub struct SearchOptions {
+    /// Minimum number of shared hashes for a match
+    pub threshold: usize,
+    pub min_containment: f64,
+    pub max_results: usize,
+    /// Metadata columns to add to each match
+    pub columns: Vec<String>,
+}
+
+/// Hashes looked up at a time when searching, so progress can be reported
+const LOOKUP_CHUNK: usize = 10_000;
+
+/// Progress of a running query, reported for background jobs.
+#[derive(Default)]
+pub struct Progress {
+    stage: Mutex<Option<&'static str>>,
+    hashes_processed: AtomicUsize,
+    hashes_total: AtomicUsize,
+}
+
+/// Progress of a query, as reported by `GET /jobs/{id}`.
+#[derive(Serialize)]
+pub struct ProgressInfo {
+    /// Query stage running, with the same names as the stage metrics
+    stage: Option<&'static str>,
+    /// Query hashes looked up in the index so far
+    hashes_processed: usize,
+    hashes_total: usize,
+}
+
+impl Progress {
+    fn start(&self, stage: &'static str, hashes_total: usize) {
+        self.hashes_total.store(hashes_total, Ordering::Relaxed);
+        self.stage(stage);
+    }
+
+    fn stage(&self, stage: &'static str) {
+        *self.stage.lock().unwrap() = Some(stage);
+    }
+
+    fn advance(&self, hashes: usize) {
+        self.hashes_processed.fetch_add(hashes, Ordering::Relaxed);
+    }
+
+    pub fn info(&self) -> ProgressInfo {
+        ProgressInfo {
+            stage: *self.stage.lock().unwrap(),
+            hashes_processed: self.hashes_processed.load(Ordering::Relaxed),
+            hashes_total: self.hashes_total.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Split a query into sketches of at most `size` hashes. There is always at least one.
+fn query_chunks(mh: &KmerMinHash, size: usize) -> Vec<KmerMinHash> {
+    if mh.size() <= size {
+        return vec![mh.clone()];
+    }
+
+    let mut template = mh.clone();
+    template.disable_abundance();
+    template.clear();
+    mh.mins()
+        .chunks(size)
+        .map(|hashes| {
+            let mut chunk = template.clone();
+            for &hash in hashes {
+                chunk.add_hash(hash);
+            }
+            chunk
+        })
+        .collect()
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct QueryInfo {
+    name: String,
+    md5: String,
+    ksize: usize,
+    scaled: u64,
+    /// Number of hashes in the query sketch
+    size: usize,
+    /// What was done to the signature to search it, like downsampling
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    adjustments: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SearchMatch {
+    accession: String,
+    intersect_hashes: usize,
+    containment: f64,
+    /// ANI estimated from the containment
+    containment_ani: f64,
+    /// Containment of the smaller of query and dataset, if dataset sizes are known
+    max_containment: Option<f64>,
+    max_containment_ani: Option<f64>,
+    jaccard: Option<f64>,
+    /// Fraction of the query abundance in the dataset, if the query has abundances
+    weighted_containment: Option<f64>,
+    estimated_bp: usize,
+    /// Selected metadata columns for the dataset
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    metadata: BTreeMap<String, String>,
+}
+
+/// Gather matches as CSV lines, and what was done to the query to run it.
+#[derive(Default)]
+pub struct GatherResults {
+    adjustments: Vec<String>,
+    lines: Vec<String>,
+}
+
+impl GatherResults {
+    pub fn adjustments(&self) -> &[String] {
+        &self.adjustments
+    }
+
+    pub fn to_csv(&self) -> &[String] {
+        &self.lines
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SearchResults {
+    index: String,
+    query: QueryInfo,
+    threshold_bp: usize,
+    min_containment: f64,
+    matches: Vec<SearchMatch>,
+    /// Metadata columns included in the matches, in the order requested
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    columns: Vec<String>,
+    /// Whether the results came from the cache
+    #[serde(default)]
+    cached: bool,
+}
+
+impl SearchResults {
+    pub fn cached(&self) -> bool {
+        self.cached
+    }
+
+    pub fn adjustments(&self) -> &[String] {
+        &self.query.adjustments
+    }
+
+    pub fn to_csv(&self) -> Vec<String> {
+        let mut header = vec![
+            "SRA accession",
+            "containment",
+            "containment_ani",
+            "max_containment",
+            "max_containment_ani",
+            "jaccard",
+            "weighted_containment",
+        ];
+        header.extend(self.columns.iter().map(|c| c.as_str()));
+
+        let optional = |v: Option<f64>| v.map(|v| v.to_string()).unwrap_or_default();
+
+        let mut csv = vec![csv_row(header)];
+        csv.extend(self.matches.iter().map(|m| {
+            let stats = [
+                m.containment.to_string(),
+                m.containment_ani.to_string(),
+                optional(m.max_containment),
+                optional(m.max_containment_ani),
+                optional(m.jaccard),
+                optional(m.weighted_containment),
+            ];
+            let mut row = vec![m.accession.as_str()];
+            row.extend(stats.iter().map(|s| s.as_str()));
+            row.extend(
+                self.columns
+                    .iter()
+                    .map(|c| m.metadata.get(c).map(|v| v.as_str()).unwrap_or_default()),
+            );
+            csv_row(row)
+        }));
+        csv
+    }
+}
+
+/// Format a CSV row, quoting fields as needed.
+/// Metadata values often include commas, unlike the other fields.
+fn csv_row<'a>(fields: impl IntoIterator<Item = &'a str>) -> String {
+    fields
+        .into_iter()
+        .map(|field| {
+            if field.contains([',', '"', '\n', '\r']) {
+                Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+            } else {
+                Cow::Borrowed(field)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Results for one query in a batch, or why it failed.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum BatchResult {
+    Ok(SearchResults),
+    Err { name: String, error: String },
+}
+
+#[derive(Serialize)]
+pub struct BatchResults {
+    index: String,
+    /// Results for each query, in the same order as the queries
+    results: Vec<BatchResult>,
+}
+
+/// Hashes shared by a query and a dataset, as reported by `/explain`.
+#[derive(Serialize)]
+pub struct ExplainResults {
+    index: String,
+    query: QueryInfo,
+    /// Name of the dataset in the index
+    dataset: String,
+    intersect_hashes: usize,
+    hashes: Vec<u64>,
+}
+
+/// Accessions of the datasets containing each hash, as reported by `/hash/{value}`.
+#[derive(Serialize)]
+pub struct LookupResults {
+    index: String,
+    datasets: BTreeMap<u64, Vec<String>>,
+}
+
+/// Summary of a served index, as reported by `/indexes` and `/info`.
+#[derive(Serialize)]
+pub struct IndexInfo {
+    name: String,
+    ksize: usize,
+    scaled: usize,
+    molecule: String,
+    default: bool,
+    /// threshold_bp used if the client doesn't set one
+    threshold_bp: usize,
+    /// Number of datasets in the index, if a manifest was provided
+    datasets: Option<usize>,
+    /// Last time the index was modified, in seconds since the Unix epoch
+    built_at: Option<u64>,
+    /// Metadata columns clients can include in results
+    metadata_columns: Vec<String>,
+}
+
+/// Result of probing an index, as reported by `/ready`.
+#[derive(Serialize)]
+pub struct Readiness {
+    name: String,
+    ready: bool,
+    /// Number of datasets in the index, if a manifest was provided
+    datasets: Option<usize>,
+    ksize: usize,
+    scaled: usize,
+    /// Time to answer the probe query, in milliseconds
+    latency_ms: u128,
+    error: Option<String>,
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.ready
+    }
+}
+
+/// A RevIndex being served, together with the sketch template queries
+/// need to be compatible with.
+pub struct Index {
+    name: String,
+    path: PathBuf,
+    db: Arc<RevIndex>,
+    template: Arc<KmerMinHash>,
+    threshold: usize,
+    datasets: Option<usize>,
+    built_at: Option<u64>,
+    names: Arc<NameResolver>,
+    sizes: Option<DatasetSizes>,
+    metadata: Option<Metadata>,
+    cache: Option<Arc<ResultCache>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Index {
+    pub fn open(
+        spec: IndexSpec,
+        defaults: &IndexDefaults,
+        cache: Option<Arc<ResultCache>>,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self, BoxError> {
+        let ksize = spec.ksize.unwrap_or(defaults.ksize);
+        let scaled = spec.scaled.unwrap_or(defaults.scaled);
+
+        let max_hash = max_hash_for_scaled(scaled as u64);
+        let mh = KmerMinHash::builder()
+            .num(0)
+            .max_hash(max_hash)
+            .ksize(ksize as u32)
+            .build();
+
+        let threshold = defaults.threshold_bp / mh.scaled() as usize;
+
+        let db = RevIndex::open(&spec.path, true)
+            .map_err(|e| format!("Error opening DB for index '{}': {e}", spec.name))?;
+
+        // The index dir only changes when building or updating the index
+        let built_at = std::fs::metadata(&spec.path)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|d| d.as_secs());
+
+        let names = NameResolver::new(spec.names.unwrap_or_default(), spec.name_pattern.as_deref())
+            .map_err(|e| format!("Invalid name_pattern for index '{}': {e}", spec.name))?;
+
+        let (datasets, names, sizes) = match &spec.manifest {
+            Some(path) => {
+                let data = std::fs::read(path).map_err(|e| {
+                    format!("Error opening manifest for index '{}': {e}", spec.name)
+                })?;
+                let manifest = Manifest::from_reader(&data[..]).map_err(|e| {
+                    format!("Error reading manifest for index '{}': {e}", spec.name)
+                })?;
+                let sizes = DatasetSizes::from_reader(&data[..], ksize as u32, scaled as u64)
+                    .map_err(|e| {
+                        format!("Error reading manifest for index '{}': {e}", spec.name)
+                    })?;
+                (
+                    Some(manifest.iter().count()),
+                    names.with_manifest(&manifest),
+                    Some(sizes),
+                )
+            }
+            None if names.source() == NameSource::Location => {
+                return Err(format!(
+                    "Index '{}' needs a manifest to use locations as names",
+                    spec.name
+                )
+                .into())
+            }
+            None => (None, names, None),
+        };
+
+        let metadata = match &spec.metadata {
+            Some(path) => {
+                let key = spec.metadata_key.as_deref().unwrap_or("Run");
+                let metadata = Metadata::from_path(path, key, spec.metadata_columns.as_deref())
+                    .map_err(|e| {
+                        format!("Error reading metadata for index '{}': {e}", spec.name)
+                    })?;
+                tracing::info!(
+                    index = spec.name.as_str(),
+                    "Loaded metadata for {} datasets",
+                    metadata.datasets()
+                );
+                Some(metadata)
+            }
+            None => None,
+        };
+
+        Ok(Index {
+            name: spec.name,
+            path: spec.path,
+            db: Arc::new(db),
+            template: Arc::new(mh),
+            threshold,
+            datasets,
+            built_at,
+            names: Arc::new(names),
+            sizes,
+            metadata,
+            cache,
+            metrics,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    pub fn scaled(&self) -> usize {
+        self.template.scaled() as usize
+    }
+
+    pub fn info(&self, default: bool) -> IndexInfo {
+        IndexInfo {
+            name: self.name.clone(),
+            ksize: self.template.ksize(),
+            scaled: self.scaled(),
+            molecule: self.template.hash_function().to_string(),
+            default,
+            threshold_bp: self.threshold * self.scaled(),
+            datasets: self.datasets,
+            built_at: self.built_at,
+            metadata_columns: self
+                .metadata
+                .as_ref()
+                .map(|m| m.columns().to_vec())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Check metadata columns requested by a client are available for this index.
+    pub fn check_columns(&self, columns: &[String]) -> Result<(), String> {
+        match &self.metadata {
+            _ if columns.is_empty() => Ok(()),
+            Some(metadata) => metadata.check_columns(columns),
+            None => Err(format!("Index '{}' has no metadata", self.name)),
+        }
+    }
+
+    /// Metadata for a dataset, given its accession.
+    fn metadata_for(&self, accession: &str, columns: &[String]) -> BTreeMap<String, String> {
+        match &self.metadata {
+            Some(metadata) if !columns.is_empty() => metadata.select(accession, columns),
+            _ => BTreeMap::new(),
+        }
+    }
+
+    /// Add the requested metadata columns to search results.
+    /// Done after caching, so cached results can be used with any columns.
+    fn add_metadata(&self, results: &mut SearchResults, columns: &[String]) {
+        for m in &mut results.matches {
+            m.metadata = self.metadata_for(&m.accession, columns);
+        }
+        results.columns = columns.to_vec();
+    }
+
+    /// Check the index is answering by running a tiny query within `deadline`.
+    pub async fn probe(&self, deadline: Duration) -> Readiness {
+        let db = self.db.clone();
+        let mut query = self.template.as_ref().clone();
+        for hash in [1, 2, 3] {
+            query.add_hash(hash);
+        }
+
+        let start = Instant::now();
+        let probe = self.metrics.spawn_blocking(move || {
+            let counter = db.counter_for_query(&query);
+            db.matches_from_counter(counter, 1).len()
+        });
+
+        let error = match tokio::time::timeout(deadline, probe).await {
+            Ok(Ok(_)) => None,
+            Ok(Err(e)) => Some(format!("Probe query failed: {e}")),
+            Err(_) => Some(format!(
+                "Probe query took longer than {}s",
+                deadline.as_secs_f64()
+            )),
+        };
+
+        Readiness {
+            name: self.name.clone(),
+            ready: error.is_none(),
+            datasets: self.datasets,
+            ksize: self.template.ksize(),
+            scaled: self.scaled(),
+            latency_ms: start.elapsed().as_millis(),
+            error,
+        }
+    }
+
+    /// Search a query, reporting how far it got in `progress`.
+    pub async fn search(
+        &self,
+        query: Signature,
+        options: SearchOptions,
+        progress: Arc<Progress>,
+    ) -> Result<SearchResults, BoxError> {
+        let name = query.name();
+        let (mh, adjustments) = prepare_query(&self.template, &query)?;
+        let cache_key = self
+            .cache
+            .as_ref()
+            .map(|_| ResultCache::key(&self.name, self.built_at, &mh, &options));
+
+        if let (Some(cache), Some(key)) = (&self.cache, &cache_key) {
+            if let Some(mut results) = cache.get(key).await {
+                results.query.name = name;
+                results.query.adjustments = adjustments;
+                results.cached = true;
+                self.add_metadata(&mut results, &options.columns);
+                return Ok(results);
+            }
+        }
+
+        let db = self.db.clone();
+        let threshold = options.threshold;
+        let min_containment = options.min_containment;
+        let max_results = options.max_results;
+        let metrics = self.metrics.clone();
+        let index_name = self.name.clone();
+
+        let (matches, query_info, weighted) = self
+            .metrics
+            .spawn_blocking(move || {
+                progress.start("counter_for_query", mh.size());
+                let counter = metrics.time_stage(&index_name, "counter_for_query", || {
+                    let chunks = query_chunks(&mh, LOOKUP_CHUNK);
+                    let mut counter = db.counter_for_query(&chunks[0]);
+                    progress.advance(chunks[0].size());
+                    for chunk in &chunks[1..] {
+                        counter += db.counter_for_query(chunk);
+                        progress.advance(chunk.size());
+                    }
+                    counter
+                });
+                // Only the reported datasets are looked up in the index
+                let query_size = mh.size();
+                let matched: Vec<_> = counter
+                    .most_common()
+                    .into_iter()
+                    .filter(|&(_, size)| {
+                        size >= threshold && size as f64 / query_size as f64 >= min_containment
+                    })
+                    .take(max_results)
+                    .collect();
+                progress.stage("matches_from_counter");
+                let matches = metrics.time_stage(&index_name, "matches_from_counter", || {
+                    db.matches_from_counter(matched.iter().copied().collect(), threshold)
+                });
+                let weighted = if mh.track_abundance() && !matched.is_empty() {
+                    progress.stage("weighted_containment");
+                    Some(metrics.time_stage(&index_name, "weighted_containment", || {
+                        weighted_containment_for(&db, matched, &mh)
+                    })?)
+                } else {
+                    None
+                };
+                let query_info = QueryInfo {
+                    name,
+                    md5: mh.md5sum(),
+                    ksize: mh.ksize(),
+                    scaled: mh.scaled(),
+                    size: mh.size(),
+                    adjustments,
+                };
+                Ok::<_, BoxError>((matches, query_info, weighted))
+            })
+            .await??;
+
+        self.metrics
+            .observe_query(&self.name, "search", query_info.size, matches.len());
+
+        let query_size = query_info.size;
+        let scaled = query_info.scaled as usize;
+        let matches = matches
+            .into_iter()
+            .map(|(name, size)| {
+                let match_size = self.sizes.as_ref().and_then(|s| s.get(&name));
+                let stats = MatchStats::new(size, query_size, match_size, query_info.ksize);
+                SearchMatch {
+                    accession: self.names.resolve(&name).into(),
+                    intersect_hashes: size,
+                    containment: stats.containment,
+                    containment_ani: stats.containment_ani,
+                    max_containment: stats.max_containment,
+                    max_containment_ani: stats.max_containment_ani,
+                    jaccard: stats.jaccard,
+                    weighted_containment: weighted
+                        .as_ref()
+                        .map(|w| w.get(&name).copied().unwrap_or_default()),
+                    estimated_bp: size * scaled,
+                    metadata: BTreeMap::new(),
+                }
+            })
+            .collect();
+
+        let mut results = SearchResults {
+            index: self.name.clone(),
+            query: query_info,
+            threshold_bp: threshold * scaled,
+            min_containment: options.min_containment,
+            matches,
+            columns: vec![],
+            cached: false,
+        };
+
+        if let (Some(cache), Some(key)) = (&self.cache, cache_key) {
+            cache.insert(key, &results).await;
+        }
+
+        self.add_metadata(&mut results, &options.columns);
+        Ok(results)
+    }
+
+    /// Find which query hashes are shared with a dataset, given by name or accession.
+    pub async fn explain(
+        &self,
+        query: Signature,
+        dataset: String,
+    ) -> Result<ExplainResults, BoxError> {
+        let db = self.db.clone();
+        let name = query.name();
+        let (mh, adjustments) = prepare_query(&self.template, &query)?;
+        let names = self.names.clone();
+        let metrics = self.metrics.clone();
+        let index_name = self.name.clone();
+
+        let (explanation, query_info) = self
+            .metrics
+            .spawn_blocking(move || -> Result<_, BoxError> {
+                let explanation = metrics.time_stage(&index_name, "explain", || {
+                    explain(&db, &mh, &dataset, &names)
+                })?;
+                let query_info = QueryInfo {
+                    name,
+                    md5: mh.md5sum(),
+                    ksize: mh.ksize(),
+                    scaled: mh.scaled(),
+                    size: mh.size(),
+                    adjustments,
+                };
+                Ok((explanation, query_info))
+            })
+            .await??;
+
+        Ok(ExplainResults {
+            index: self.name.clone(),
+            query: query_info,
+            dataset: explanation.dataset,
+            intersect_hashes: explanation.hashes.len(),
+            hashes: explanation.hashes,
+        })
+    }
+
+    /// Find the datasets containing each hash.
+    pub async fn lookup(&self, hashes: Vec<u64>) -> Result<LookupResults, BoxError> {
+        let db = self.db.clone();
+        let template = self.template.clone();
+        let names = self.names.clone();
+        let metrics = self.metrics.clone();
+        let index_name = self.name.clone();
+
+        let datasets = self
+            .metrics
+            .spawn_blocking(move || -> Result<_, BoxError> {
+                Ok(metrics.time_stage(&index_name, "lookup", || {
+                    lookup(&db, &template, &hashes, &names)
+                })?)
+            })
+            .await??;
+
+        Ok(LookupResults {
+            index: self.name.clone(),
+            datasets,
+        })
+    }
+
+    /// Search all queries, running at most `max_concurrent` of them in parallel
+    /// on the blocking pool.
+    ///
+    /// A query failing doesn't fail the batch, it is reported with its results instead.
+    pub async fn search_batch(
+        self: &Arc<Self>,
+        queries: Vec<Signature>,
+        options: SearchOptions,
+        max_concurrent: usize,
+    ) -> BatchResults {
+        let permits = Arc::new(Semaphore::new(max_concurrent.max(1)));
+        let mut tasks = Vec::with_capacity(queries.len());
+        for query in queries {
+            let name = query.name();
+            let index = self.clone();
+            let options = options.clone();
+            let permits = permits.clone();
+            let task = tokio::spawn(async move {
+                let _permit = permits.acquire_owned().await?;
+                index.search(query, options, Default::default()).await
+            });
+            tasks.push((name, task));
+        }
+
+        let mut results = Vec::with_capacity(tasks.len());
+        for (name, task) in tasks {
+            let result = match task.await {
+                Ok(Ok(res)) => BatchResult::Ok(res),
+                Ok(Err(e)) => BatchResult::Err {
+                    name,
+                    error: e.to_string(),
+                },
+                Err(e) => {
+                    tracing::error!(index = self.name(), "Error processing query: {e}");
+                    BatchResult::Err {
+                        name,
+                        error: format!("Something went wrong: {e}"),
+                    }
+                }
+            };
+            results.push(result);
+        }
+
+        BatchResults {
+            index: self.name.clone(),
+            results,
+        }
+    }
+
+    /// Gather the query against the index, as CSV lines.
+    /// Abundance-weighted columns are only filled if the query has abundances.
+    /// Metadata `columns` are added after the gather columns.
+    /// Hashes are looked up all at once, so `progress` only advances when they are done.
+    pub async fn gather(
+        &self,
+        query: Signature,
+        columns: &[String],
+        progress: Arc<Progress>,
+    ) -> Result<GatherResults, BoxError> {
+        let db = self.db.clone();
+        let threshold = self.threshold;
+        let (mh, adjustments) = prepare_query(&self.template, &query)?;
+        let metrics = self.metrics.clone();
+        let index_name = self.name.clone();
+
+        for adjustment in &adjustments {
+            tracing::info!(index = self.name(), "{}: {adjustment}", query.name());
+        }
+
+        let (matches, abundances, query_size, scaled) = self
+            .metrics
+            .spawn_blocking(move || -> Result<_, BoxError> {
+                progress.start("prepare_gather_counters", mh.size());
+                let (counter, query_colors, hash_to_color) =
+                    metrics.time_stage(&index_name, "prepare_gather_counters", || {
+                        db.prepare_gather_counters(&mh)
+                    });
+                progress.advance(mh.size());
+                progress.stage("gather");
+                let matches = metrics.time_stage(&index_name, "gather", || {
+                    db.gather(counter, query_colors, hash_to_color, threshold, &mh, None)
+                })?;
+                // Not calculated by the RevIndex gather yet
+                let abundances = if mh.track_abundance() {
+                    progress.stage("gather_abundances");
+                    let sigs: Vec<_> = matches.iter().map(|m| m.get_match()).collect();
+                    metrics.time_stage(&index_name, "gather_abundances", || {
+                        gather_abundances(&mh, &sigs)
+                    })
+                } else {
+                    vec![]
+                };
+                Ok((matches, abundances, mh.size(), mh.scaled() as usize))
+            })
+            .await??;
+
+        self.metrics
+            .observe_query(&self.name, "gather", query_size, matches.len());
+
+        let mut wtr = csv::Writer::from_writer(vec![]);
+        let mut header: Vec<&str> = vec![
+            "gather_result_rank",
+            "name",
+            "md5",
+            "intersect_bp",
+            "unique_intersect_bp",
+            "f_match",
+            "f_unique_to_query",
+            "remaining_bp",
+            "f_unique_weighted",
+            "average_abund",
+            "median_abund",
+            "std_abund",
+        ];
+        header.extend(columns.iter().map(|c| c.as_str()));
+        wtr.write_record(header)?;
+
+        // `f_orig_query` is calculated over the hashes still unassigned
+        // at each rank, so it is the fraction unique to this match.
+        let mut remaining = query_size;
+        for (rank, match_) in matches.iter().enumerate() {
+            let f_unique_to_query = match_.f_orig_query();
+            let unique = (f_unique_to_query * query_size as f64).round() as usize;
+            remaining = remaining.saturating_sub(unique);
+
+            let mut record = vec![
+                rank.to_string(),
+                match_.name().into(),
+                match_.md5().into(),
+                match_.intersect_bp().to_string(),
+                (unique * scaled).to_string(),
+                match_.f_match().to_string(),
+                f_unique_to_query.to_string(),
+                (remaining * scaled).to_string(),
+            ];
+            // Empty if the query has no abundances
+            match abundances.get(rank) {
+                Some(abund) => record.extend([
+                    abund.f_unique_weighted.to_string(),
+                    abund.average_abund.to_string(),
+                    abund.median_abund.to_string(),
+                    abund.std_abund.to_string(),
+                ]),
+                None => record.extend(std::iter::repeat(String::new()).take(4)),
+            }
+            let metadata = self.metadata_for(self.names.resolve(match_.name()), columns);
+            record.extend(
+                columns
+                    .iter()
+                    .map(|c| metadata.get(c).cloned().unwrap_or_default()),
+            );
+            wtr.write_record(&record)?;
+        }
+
+        let data = String::from_utf8(wtr.into_inner()?)?;
+        Ok(GatherResults {
+            adjustments,
+            lines: data.lines().map(|l| l.into()).collect(),
+        })
+    }
+
+    /// Parse all signatures in a JSON file (possibly gzipped) or sourmash zip collection.
+    ///
+    /// Signatures are not checked for compatibility with the index,
+    /// so each query can fail separately.
+    pub fn parse_sigs(raw_data: &[u8]) -> Result<Vec<Signature>, BoxError> {
+        if !raw_data.starts_with(b"PK\x03\x04") {
+            let sigs = Signature::from_reader(raw_data)?;
+            if sigs.is_empty() {
+                return Err("No signatures in upload".into());
+            }
+            return Ok(sigs);
+        }
+
+        let archive = piz::ZipArchive::new(raw_data)?;
+        let mut sigs = vec![];
+        for entry in archive.entries() {
+            let path = entry.path.as_str();
+            if entry.is_file() && (path.ends_with(".sig") || path.ends_with(".sig.gz")) {
+                let rdr = archive.read(entry)?;
+                sigs.extend(
+                    Signature::from_reader(rdr)
+                        .map_err(|e| format!("Error parsing {path}: {e}"))?,
+                );
+            }
+        }
+
+        if sigs.is_empty() {
+            Err("No signatures found in zip file".into())
+        } else {
+            Ok(sigs)
+        }
+    }
+
+    /// Sketch FASTA/FASTQ inputs into a query for this index.
+    ///
+    /// With `abundance` the query keeps the number of times each k-mer was seen.
+    pub async fn sketch<R: Read + Send + 'static>(
+        &self,
+        name: Option<String>,
+        inputs: Vec<R>,
+        abundance: bool,
+    ) -> Result<Signature, BoxError> {
+        let mut template = self.template.as_ref().clone();
+        if abundance {
+            template.enable_abundance()?;
+        }
+        let metrics = self.metrics.clone();
+        let index_name = self.name.clone();
+
+        self.metrics
+            .spawn_blocking(move || {
+                metrics.time_stage(&index_name, "sketch", || {
+                    sketch_sequences(&template, name, inputs)
+                })
+            })
+            .await?
+    }
+
+    /// Build a query from FracMinHash hashes computed with `scaled`.
+    ///
+    /// Hashes from smaller scaled values are downsampled to the index scaled.
+    pub fn query_from_hashes(
+        &self,
+        name: String,
+        hashes: &[u64],
+        scaled: u64,
+        ksize: Option<u32>,
+    ) -> Result<Signature, BoxError> {
+        let mut mh = self.template.as_ref().clone();
+
+        if scaled == 0 || scaled > mh.scaled() {
+            return Err(format!(
+                "Hashes must be computed with scaled between 1 and {}",
+                mh.scaled()
+            )
+            .into());
+        }
+        if let Some(ksize) = ksize {
+            if ksize != mh.ksize() as u32 {
+                return Err(format!("Expected hashes for k={}", mh.ksize()).into());
+            }
+        }
+
+        // Hashes over the index max_hash are ignored, downsampling the query
+        mh.add_many(hashes)?;
+
+        Ok(Signature::builder()
+            .name(Some(name))
+            .signatures(vec![Sketch::MinHash(mh)])
+            .hash_function("DNA")
+            .build())
+    }
+
+    /// Parse hashes from a JSON array, or a stream of little-endian u64 values.
+    pub fn parse_hashes(raw_data: &[u8], json: bool) -> Result<Vec<u64>, BoxError> {
+        if json {
+            return Ok(serde_json::from_slice(raw_data)?);
+        }
+
+        if raw_data.len() % 8 != 0 {
+            return Err("Binary hashes must be a multiple of 8 bytes".into());
+        }
+        Ok(raw_data
+            .chunks_exact(8)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+            .collect())
+    }
+
+    pub fn parse_sig(&self, raw_data: &[u8]) -> Result<Signature, BoxError> {
+        let Some(sig) = Signature::from_reader(raw_data)?.into_iter().next() else {
+            return Err("No signatures in upload".into());
+        };
+        // The sketch is prepared again when searching, this only checks there is one
+        prepare_query(&self.template, &sig)?;
+        Ok(sig)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_spec_with_name_and_options() {
+        let spec: IndexSpec = "sra=/data/sra.rocksdb,ksize=31,scaled=10000,manifest=sra.csv"
+            .parse()
+            .unwrap();
+        assert_eq!(spec.name, "sra");
+        assert_eq!(spec.path, PathBuf::from("/data/sra.rocksdb"));
+        assert_eq!(spec.ksize, Some(31));
+        assert_eq!(spec.scaled, Some(10000));
+        assert_eq!(spec.manifest, Some(PathBuf::from("sra.csv")));
+        assert!(spec.metadata.is_none());
+    }
+
+    #[test]
+    fn index_spec_name_from_path() {
+        let spec: IndexSpec = "/data/genbank.rocksdb".parse().unwrap();
+        assert_eq!(spec.name, "genbank");
+        assert_eq!(spec.path, PathBuf::from("/data/genbank.rocksdb"));
+        assert_eq!(spec.ksize, None);
+        assert_eq!(spec.scaled, None);
+    }
+
+    #[test]
+    fn index_spec_names_and_metadata() {
+        let spec: IndexSpec =
+            "sra=sra.rocksdb,names=location,metadata=runinfo.csv,metadata_key=Run_acc"
+                .parse()
+                .unwrap();
+        assert_eq!(spec.names, Some(NameSource::Location));
+        assert_eq!(spec.metadata, Some(PathBuf::from("runinfo.csv")));
+        assert_eq!(spec.metadata_key.as_deref(), Some("Run_acc"));
+    }
+
+    #[test]
+    fn index_spec_escaped_comma() {
+        let spec: IndexSpec = r"sra=sra.rocksdb,name_pattern=^(SRR\d{6\,}),names=name"
+            .parse()
+            .unwrap();
+        assert_eq!(spec.name_pattern.as_deref(), Some(r"^(SRR\d{6,})"));
+        assert_eq!(spec.names, Some(NameSource::Name));
+    }
+
+    #[test]
+    fn index_spec_invalid() {
+        assert!("".parse::<IndexSpec>().is_err());
+        assert!("sra=".parse::<IndexSpec>().is_err());
+        assert!("=sra.rocksdb".parse::<IndexSpec>().is_err());
+        assert!("sra=sra.rocksdb,ksize=abc".parse::<IndexSpec>().is_err());
+        assert!("sra=sra.rocksdb,color=blue".parse::<IndexSpec>().is_err());
+        assert!("sra=sra.rocksdb,ksize".parse::<IndexSpec>().is_err());
+        assert!("sra=sra.rocksdb,names=other".parse::<IndexSpec>().is_err());
+    }
+
+    #[test]
+    fn parse_hashes_binary() {
+        let raw: Vec<u8> = [1u64, u64::MAX, 42]
+            .iter()
+            .flat_map(|h| h.to_le_bytes())
+            .collect();
+        assert_eq!(
+            Index::parse_hashes(&raw, false).unwrap(),
+            vec![1, u64::MAX, 42]
+        );
+        assert!(Index::parse_hashes(&[], false).unwrap().is_empty());
+        assert!(Index::parse_hashes(&raw[..12], false).is_err());
+    }
+
+    #[test]
+    fn parse_hashes_json() {
+        assert_eq!(
+            Index::parse_hashes(b"[1, 18446744073709551615]", true).unwrap(),
+            vec![1, u64::MAX]
+        );
+        assert!(Index::parse_hashes(b"[-1]", true).is_err());
+        assert!(Index::parse_hashes(b"1, 2", true).is_err());
+    }
+
+    #[test]
+    fn parse_sigs_empty() {
+        let err = Index::parse_sigs(b"[ ]\n\n").unwrap_err();
+        assert_eq!(err.to_string(), "No signatures in upload");
+        assert!(Index::parse_sigs(b"").is_err());
+    }
+}
@@ -0,0 +1,416 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use sourmash::signature::Signature;
+use tokio::sync::Semaphore;
+use tower::BoxError;
+
+use crate::config;
+use crate::index::{GatherResults, Index, Progress, ProgressInfo, SearchOptions, SearchResults};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobKind {
+    Search,
+    #[default]
+    Gather,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+/// Results of a finished job, kept until it expires.
+pub enum JobOutput {
+    Search(SearchResults),
+    Gather(GatherResults),
+}
+
+struct Job {
+    kind: JobKind,
+    index: String,
+    /// Submission order, used to report the position in the queue
+    seq: u64,
+    status: JobStatus,
+    submitted: Instant,
+    finished: Option<Instant>,
+    output: Option<Arc<JobOutput>>,
+    error: Option<String>,
+    progress: Arc<Progress>,
+}
+
+/// Job status, as reported by `GET /jobs/{id}`.
+#[derive(Serialize)]
+pub struct JobInfo {
+    id: String,
+    kind: JobKind,
+    index: String,
+    status: JobStatus,
+    /// Number of jobs ahead of this one, while queued
+    queue_position: Option<usize>,
+    /// Time since submission, or time it took to finish
+    elapsed_secs: f64,
+    /// Time until the results are removed, once finished
+    expires_in_secs: Option<u64>,
+    /// How far the query got, while running
+    progress: Option<ProgressInfo>,
+    error: Option<String>,
+}
+
+/// Why a job result can't be returned.
+pub enum ResultError {
+    NotFound,
+    Pending(JobStatus),
+    Failed(String),
+}
+
+/// Jobs submitted to run in the background, and their results.
+///
+/// Jobs run in submission order, at most `workers` at a time.
+/// Submissions are rejected once `max_queued` jobs are waiting,
+/// and finished jobs are removed after `result_ttl` by a periodic task.
+pub struct Jobs {
+    jobs: Mutex<HashMap<String, Job>>,
+    workers: Arc<Semaphore>,
+    max_queued: usize,
+    result_ttl: Duration,
+    next_seq: AtomicU64,
+    ids: RandomState,
+}
+
+impl Jobs {
+    pub fn new(config: &config::Jobs) -> Self {
+        Jobs {
+            jobs: Default::default(),
+            workers: Arc::new(Semaphore::new(config.workers)),
+            max_queued: config.max_queued,
+            result_ttl: Duration::from_secs(config.result_ttl_secs),
+            next_seq: AtomicU64::new(0),
+            ids: RandomState::new(),
+        }
+    }
+
+    /// Queue a job, returning its id. Fails if the queue is full.
+    pub fn submit(
+        self: &Arc<Self>,
+        kind: JobKind,
+        index: Arc<Index>,
+        query: Signature,
+        options: SearchOptions,
+    ) -> Result<String, String> {
+        let progress = Arc::new(Progress::default());
+        let name = index.name().to_string();
+        let task_progress = progress.clone();
+        let task = async move {
+            match kind {
+                JobKind::Search => index
+                    .search(query, options, task_progress)
+                    .await
+                    .map(JobOutput::Search),
+                JobKind::Gather => index
+                    .gather(query, &options.columns, task_progress)
+                    .await
+                    .map(JobOutput::Gather),
+            }
+        };
+        self.spawn(kind, name, progress, task)
+    }
+
+    /// Queue a job running `task` once a worker is free.
+    fn spawn<F>(
+        self: &Arc<Self>,
+        kind: JobKind,
+        index: String,
+        progress: Arc<Progress>,
+        task: F,
+    ) -> Result<String, String>
+    where
+        F: Future<Output = Result<JobOutput, BoxError>> + Send + 'static,
+    {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let id = self.new_id(seq);
+
+        {
+            let mut jobs = self.jobs.lock().unwrap();
+            self.expire(&mut jobs);
+
+            let queued = jobs
+                .values()
+                .filter(|job| job.status == JobStatus::Queued)
+                .count();
+            if queued >= self.max_queued {
+                return Err(format!(
+                    "Job queue is full ({queued} jobs waiting), try again later"
+                ));
+            }
+
+            jobs.insert(
+                id.clone(),
+                Job {
+                    kind,
+                    index,
+                    seq,
+                    status: JobStatus::Queued,
+                    submitted: Instant::now(),
+                    finished: None,
+                    output: None,
+                    error: None,
+                    progress,
+                },
+            );
+        }
+
+        let jobs = self.clone();
+        let job_id = id.clone();
+        tokio::spawn(async move {
+            let _permit = jobs.workers.clone().acquire_owned().await;
+            jobs.update(&job_id, |job| job.status = JobStatus::Running);
+
+            let output = task.await;
+
+            jobs.update(&job_id, |job| {
+                job.finished = Some(Instant::now());
+                match output {
+                    Ok(output) => {
+                        job.status = JobStatus::Done;
+                        job.output = Some(Arc::new(output));
+                    }
+                    Err(e) => {
+                        tracing::error!(job = job_id.as_str(), "Error running job: {e}");
+                        job.status = JobStatus::Failed;
+                        job.error = Some(e.to_string());
+                    }
+                }
+            });
+        });
+
+        Ok(id)
+    }
+
+    pub fn info(&self, id: &str) -> Option<JobInfo> {
+        let mut jobs = self.jobs.lock().unwrap();
+        self.expire(&mut jobs);
+
+        let job = jobs.get(id)?;
+        let queue_position = (job.status == JobStatus::Queued).then(|| {
+            jobs.values()
+                .filter(|other| other.status == JobStatus::Queued && other.seq < job.seq)
+                .count()
+        });
+        let elapsed = job
+            .finished
+            .unwrap_or_else(Instant::now)
+            .duration_since(job.submitted);
+        let expires_in = job
+            .finished
+            .map(|finished| self.result_ttl.saturating_sub(finished.elapsed()).as_secs());
+
+        Some(JobInfo {
+            id: id.into(),
+            kind: job.kind,
+            index: job.index.clone(),
+            status: job.status,
+            queue_position,
+            elapsed_secs: elapsed.as_secs_f64(),
+            expires_in_secs: expires_in,
+            progress: (job.status == JobStatus::Running).then(|| job.progress.info()),
+            error: job.error.clone(),
+        })
+    }
+
+    pub fn result(&self, id: &str) -> Result<Arc<JobOutput>, ResultError> {
+        let mut jobs = self.jobs.lock().unwrap();
+        self.expire(&mut jobs);
+
+        let job = jobs.get(id).ok_or(ResultError::NotFound)?;
+        match (&job.output, &job.error) {
+            (Some(output), _) => Ok(output.clone()),
+            (None, Some(error)) => Err(ResultError::Failed(error.clone())),
+            (None, None) => Err(ResultError::Pending(job.status)),
+        }
+    }
+
+    fn update(&self, id: &str, f: impl FnOnce(&mut Job)) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(id) {
+            f(job)
+        }
+    }
+
+    /// Remove finished jobs older than `result_ttl`.
+    fn expire(&self, jobs: &mut HashMap<String, Job>) {
+        jobs.retain(|_, job| {
+            job.finished
+                .map_or(true, |finished| finished.elapsed() < self.result_ttl)
+        });
+    }
+
+    /// Remove expired results periodically, so they don't stay in memory
+    /// while the jobs API is not used. Must be called within the runtime.
+    pub fn spawn_expiry(self: &Arc<Self>) {
+        let jobs = Arc::downgrade(self);
+        let period = self
+            .result_ttl
+            .clamp(Duration::from_secs(1), Duration::from_secs(60));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                let Some(jobs) = jobs.upgrade() else {
+                    break;
+                };
+                let mut pending = jobs.jobs.lock().unwrap();
+                jobs.expire(&mut pending);
+            }
+        });
+    }
+
+    /// Job ids are random, so results can't be found by guessing ids.
+    fn new_id(&self, seq: u64) -> String {
+        let mut hi = self.ids.build_hasher();
+        hi.write_u64(seq);
+        let hi = hi.finish();
+        let mut lo = self.ids.build_hasher();
+        lo.write_u64(hi);
+        format!("{:016x}{:016x}", hi, lo.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::oneshot;
+
+    use super::*;
+
+    fn jobs(workers: usize, max_queued: usize, result_ttl_secs: u64) -> Arc<Jobs> {
+        Arc::new(Jobs::new(&config::Jobs {
+            workers,
+            max_queued,
+            result_ttl_secs,
+        }))
+    }
+
+    /// Queue a job finishing with `output` once the returned sender is used.
+    fn blocked_job(
+        jobs: &Arc<Jobs>,
+    ) -> (
+        Result<String, String>,
+        oneshot::Sender<Result<JobOutput, BoxError>>,
+    ) {
+        let (tx, rx) = oneshot::channel();
+        let task = async move { rx.await.unwrap() };
+        let id = jobs.spawn(JobKind::Gather, "sra".into(), Default::default(), task);
+        (id, tx)
+    }
+
+    async fn wait_for(jobs: &Jobs, id: &str, status: JobStatus) {
+        for _ in 0..1000 {
+            if jobs.info(id).map(|info| info.status) == Some(status) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        panic!("Job {id} never got to {status:?}");
+    }
+
+    #[tokio::test]
+    async fn status_changes() {
+        let jobs = jobs(1, 10, 60);
+        let (first, first_tx) = blocked_job(&jobs);
+        let (second, second_tx) = blocked_job(&jobs);
+        let (first, second) = (first.unwrap(), second.unwrap());
+
+        wait_for(&jobs, &first, JobStatus::Running).await;
+        let info = jobs.info(&second).unwrap();
+        assert_eq!(info.status, JobStatus::Queued);
+        assert_eq!(info.queue_position, Some(0));
+        assert!(matches!(
+            jobs.result(&first),
+            Err(ResultError::Pending(JobStatus::Running))
+        ));
+
+        first_tx
+            .send(Ok(JobOutput::Gather(Default::default())))
+            .ok();
+        wait_for(&jobs, &first, JobStatus::Done).await;
+        assert!(matches!(
+            jobs.result(&first).as_deref(),
+            Ok(JobOutput::Gather(results)) if results.to_csv().is_empty()
+        ));
+        assert!(jobs.info(&first).unwrap().expires_in_secs.is_some());
+
+        wait_for(&jobs, &second, JobStatus::Running).await;
+        second_tx.send(Err("no matches".into())).ok();
+        wait_for(&jobs, &second, JobStatus::Failed).await;
+        assert_eq!(
+            jobs.info(&second).unwrap().error.as_deref(),
+            Some("no matches")
+        );
+        assert!(matches!(jobs.result(&second), Err(ResultError::Failed(_))));
+    }
+
+    #[tokio::test]
+    async fn queue_limit() {
+        let jobs = jobs(1, 1, 60);
+        let (running, running_tx) = blocked_job(&jobs);
+        wait_for(&jobs, &running.unwrap(), JobStatus::Running).await;
+
+        let (queued, _queued_tx) = blocked_job(&jobs);
+        assert!(queued.is_ok());
+        let (rejected, _) = blocked_job(&jobs);
+        assert!(rejected.is_err());
+
+        // Running jobs don't count towards the limit
+        running_tx
+            .send(Ok(JobOutput::Gather(Default::default())))
+            .ok();
+        wait_for(&jobs, &queued.unwrap(), JobStatus::Running).await;
+        assert!(blocked_job(&jobs).0.is_ok());
+    }
+
+    #[tokio::test]
+    async fn expiry() {
+        let jobs = jobs(1, 10, 0);
+        let (id, tx) = blocked_job(&jobs);
+        let id = id.unwrap();
+        wait_for(&jobs, &id, JobStatus::Running).await;
+
+        tx.send(Ok(JobOutput::Gather(Default::default()))).ok();
+        for _ in 0..1000 {
+            if jobs.info(&id).is_none() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(jobs.info(&id).is_none());
+        assert!(matches!(jobs.result(&id), Err(ResultError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn periodic_expiry() {
+        let jobs = jobs(1, 10, 0);
+        let (id, tx) = blocked_job(&jobs);
+        let id = id.unwrap();
+        jobs.spawn_expiry();
+        tx.send(Ok(JobOutput::Gather(Default::default()))).ok();
+
+        // Without going through the jobs API, which also expires jobs
+        for _ in 0..3000 {
+            if jobs.jobs.lock().unwrap().is_empty() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        panic!("Job {id} was not expired");
+    }
+}
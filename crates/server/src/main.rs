@@ -1,132 +1,236 @@
-use std::{borrow::Cow, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+use std::{borrow::Cow, net::IpAddr, path::PathBuf, sync::Arc, time::Duration};
 
 use axum::{
-    body::{BoxBody, Bytes},
+    body::{BoxBody, Bytes, HttpBody},
     error_handling::HandleErrorLayer,
-    extract::{ContentLengthLimit, Extension},
-    http::{header, StatusCode},
+    extract::{ContentLengthLimit, Extension, Multipart, Path, Query, RawBody},
+    http::{header, HeaderMap, StatusCode},
+    middleware,
     response::{IntoResponse, Response},
     routing::{get, get_service, post},
-    Router,
+    Json, Router,
 };
-use sentry::integrations::tower::{NewSentryLayer, SentryHttpLayer};
-use sentry::integrations::tracing as sentry_tracing;
 use tokio::runtime::Runtime;
 use tower::{BoxError, ServiceBuilder};
 use tower_http::{services::ServeDir, trace::TraceLayer};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 use clap::Parser;
-use color_eyre::eyre::Result;
-use sourmash::index::revindex::{RevIndex, RevIndexOps};
-use sourmash::signature::{Signature, SigsTrait};
-use sourmash::sketch::minhash::{max_hash_for_scaled, KmerMinHash};
-use sourmash::sketch::Sketch;
+use color_eyre::eyre::{eyre, Result};
+use mastiff_core::explain::ExplainError;
+use mastiff_core::lookup::LookupError;
+use serde::{Deserialize, Serialize};
+use sourmash::signature::Signature;
+
+mod cache;
+mod config;
+mod index;
+mod jobs;
+mod metadata;
+mod metrics;
+mod observability;
+mod sketch;
+
+use crate::cache::ResultCache;
+use crate::config::{Config, Limits};
+use crate::index::{Index, IndexInfo, IndexSpec, Readiness, SearchOptions};
+use crate::jobs::{JobKind, JobOutput, Jobs, ResultError};
+use crate::metrics::Metrics;
+use crate::observability::Reporter;
+use crate::sketch::ChunkReader;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Cli {
-    /// Path to rocksdb index dir
-    #[clap(parse(from_os_str))]
-    index: PathBuf,
+    /// Indexes to serve. Either a path to a rocksdb index dir,
+    /// or `NAME=PATH[,ksize=K][,scaled=S][,manifest=CSV][,metadata=CSV][,metadata_key=COLUMN]`
+    /// `[,names=name|filename|location][,name_pattern=REGEX]`.
+    /// `names` is where match accessions come from [default: filename],
+    /// and `name_pattern` extracts them with its `accession` group or the first one.
+    /// Commas in values, like in a name_pattern, must be escaped as `\,`.
+    /// The first index is used for requests without an index name.
+    /// Replaces the indexes defined in the config file.
+    #[clap(verbatim_doc_comment)]
+    indexes: Vec<IndexSpec>,
+
+    /// Path to a TOML config file.
+    /// Options in the command line take precedence over the file.
+    #[clap(short = 'c', long = "config", parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// Path to static assets [default: assets/]
+    #[clap(short = 'a', long = "assets", parse(from_os_str))]
+    assets: Option<PathBuf>,
+
+    /// Default ksize for indexes [default: 21]
+    #[clap(short = 'k', long = "ksize")]
+    ksize: Option<u8>,
+
+    /// Default scaled for indexes [default: 1000]
+    #[clap(short = 's', long = "scaled")]
+    scaled: Option<usize>,
 
-    /// Path to static assets
-    #[clap(
-        short = 'a',
-        long = "assets",
-        parse(from_os_str),
-        default_value = "assets/"
-    )]
-    assets: PathBuf,
+    /// Address to listen on [default: 127.0.0.1]
+    #[clap(short = 'b', long = "bind")]
+    bind: Option<IpAddr>,
 
-    /// ksize
-    #[clap(short = 'k', long = "ksize", default_value = "21")]
-    ksize: u8,
+    /// port [default: 3059]
+    #[clap(short = 'p', long = "port")]
+    port: Option<u16>,
 
-    /// scaled
-    #[clap(short = 's', long = "scaled", default_value = "1000")]
-    scaled: usize,
+    /// threshold_bp [default: 50000]
+    #[clap(short = 't', long = "threshold_bp")]
+    threshold_bp: Option<usize>,
 
-    /// port
-    #[clap(short = 'p', long = "port", default_value = "3059")]
-    port: u16,
+    /// Lowest threshold_bp a client can request [default: 10000]
+    #[clap(long = "min_threshold_bp")]
+    min_threshold_bp: Option<usize>,
 
-    /// threshold_bp
-    #[clap(short = 't', long = "threshold_bp", default_value = "50000")]
-    threshold_bp: usize,
+    /// Maximum number of matches returned per query [default: 10000]
+    #[clap(long = "max_results")]
+    max_results: Option<usize>,
 }
 
-fn main() -> Result<()> {
-    let _guard = sentry::init((
-        std::env::var("SENTRY_DSN").expect("$SENTRY_DSN must be set"),
-        sentry::ClientOptions {
-            release: sentry::release_name!(),
-            traces_sample_rate: 1.0,
-            enable_profiling: true,
-            profiles_sample_rate: 1.0,
-            environment: Some(
-                std::env::var("MASTIFF_ENVIRONMENT")
-                    .unwrap_or("development".into())
-                    .into(),
-            ),
-            ..Default::default()
-        },
-    ));
+impl Cli {
+    /// Load the config file (if any) and apply command line overrides.
+    fn into_config(self) -> Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => Config::from_path(path)?,
+            None => Config::default(),
+        };
+
+        if !self.indexes.is_empty() {
+            config.indexes = self.indexes;
+        }
+        if let Some(assets) = self.assets {
+            config.assets = assets;
+        }
+        if let Some(ksize) = self.ksize {
+            config.defaults.ksize = ksize;
+        }
+        if let Some(scaled) = self.scaled {
+            config.defaults.scaled = scaled;
+        }
+        if let Some(bind) = self.bind {
+            config.bind = bind;
+        }
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(threshold_bp) = self.threshold_bp {
+            config.defaults.threshold_bp = threshold_bp;
+        }
+        if let Some(min_threshold_bp) = self.min_threshold_bp {
+            config.limits.min_threshold_bp = min_threshold_bp;
+        }
+        if let Some(max_results) = self.max_results {
+            config.limits.max_results = max_results;
+        }
 
-    tracing_subscriber::registry()
-        .with(tracing_subscriber::EnvFilter::new(
-            std::env::var("RUST_LOG").unwrap_or_else(|_| "mastiff=debug,tower_http=debug".into()),
-        ))
-        .with(tracing_subscriber::fmt::layer().json())
-        .with(sentry_tracing::layer())
-        .init();
+        config.validate()?;
+        Ok(config)
+    }
+}
 
-    let opts = Cli::parse();
+fn main() -> Result<()> {
+    let config = Cli::parse().into_config()?;
+    let reporter = Reporter::init(&config.observability);
 
-    let max_hash = max_hash_for_scaled(opts.scaled as u64);
-    let mh = KmerMinHash::builder()
-        .num(0)
-        .max_hash(max_hash)
-        .ksize(opts.ksize as u32)
-        .build();
+    let metrics = Arc::new(Metrics::new()?);
+    let cache = ResultCache::new(&config.cache)
+        .map_err(|e| eyre!(e))?
+        .map(Arc::new);
 
-    let threshold = opts.threshold_bp / mh.scaled() as usize;
+    let mut indexes: Vec<Arc<Index>> = Vec::with_capacity(config.indexes.len());
+    for spec in &config.indexes {
+        let index = Index::open(
+            spec.clone(),
+            &config.defaults,
+            cache.clone(),
+            metrics.clone(),
+        )
+        .map_err(|e| eyre!(e))?;
+        indexes.push(Arc::new(index));
+    }
 
     let state = Arc::new(State {
-        db: Arc::new(RevIndex::open(opts.index, true).expect("Error opening DB")),
-        template: Arc::new(Sketch::MinHash(mh)),
-        threshold,
+        indexes,
+        limits: config.limits.clone(),
+        request_timeout: config.request_timeout(),
+        max_concurrent_searches: config.concurrency.max_requests,
+        ready_timeout: config.ready_timeout(),
+        jobs: Arc::new(Jobs::new(&config.jobs)),
+        metrics: metrics.clone(),
     });
 
+    let shed_metrics = metrics.clone();
+    let jobs = state.jobs.clone();
+
     // Build our application by composing routes
     let app = Router::new()
         .route("/search", post(search))
+        .route("/search/:index", post(search))
+        .route("/search/hashes", post(search_hashes))
+        .route("/search/hashes/:index", post(search_hashes))
+        .route("/sequences/search", post(search_sequences))
+        .route("/sequences/search/:index", post(search_sequences))
+        .route("/stream/search", post(search_stream))
+        .route("/stream/search/:index", post(search_stream))
+        .route("/batch/search", post(search_batch))
+        .route("/batch/search/:index", post(search_batch))
+        .route("/explain", post(explain))
+        .route("/explain/:index", post(explain))
+        .route("/gather", post(gather))
+        .route("/gather/:index", post(gather))
+        .route("/jobs", post(submit_job))
+        .route("/jobs/:id", get(job_status))
+        .route("/jobs/:id/result", get(job_result))
+        .route("/hash/:value", get(lookup_hashes))
+        .route("/hash/:index/:value", get(lookup_hashes))
+        .route("/indexes", get(list_indexes))
+        .route("/info", get(server_info))
         .route("/health", get(health))
-        //.route("/gather", post(gather))
-        .fallback(get_service(ServeDir::new(opts.assets)).handle_error(handle_static_serve_error))
+        .route("/ready", get(ready))
+        .route("/metrics", get(export_metrics))
+        .route_layer(middleware::from_fn(metrics::track_requests))
+        .fallback(
+            get_service(ServeDir::new(&config.assets)).handle_error(handle_static_serve_error),
+        )
         // Add middleware to all routes
         .layer(
             ServiceBuilder::new()
-                .layer(NewSentryLayer::new_from_top())
-                .layer(SentryHttpLayer::with_transaction())
                 // Handle errors from middleware
-                .layer(HandleErrorLayer::new(handle_error))
+                .layer(HandleErrorLayer::new(move |error| {
+                    handle_error(error, shed_metrics.clone())
+                }))
                 .load_shed()
-                .concurrency_limit(200)
-                .timeout(Duration::from_secs(3600))
+                .concurrency_limit(config.concurrency.max_requests)
+                .timeout(config.request_timeout())
                 .layer(TraceLayer::new_for_http())
                 .layer(Extension(state))
+                .layer(Extension(metrics))
                 .into_inner(),
         );
 
+    let app = reporter.instrument(app);
+
+    let app = match config.cors.layer() {
+        Some(cors) => app.layer(cors),
+        None => app,
+    };
+
     // Create the runtime
     let rt = Runtime::new()?;
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], opts.port));
+    let addr = config.addr();
     tracing::debug!("listening on {}", addr);
 
     // Spawn the root task
     rt.block_on(async {
+        jobs.spawn_expiry();
+        if let Some(cache) = &cache {
+            cache.spawn_cleanup();
+        }
+
         // Run our app with hyper
         axum::Server::bind(&addr)
             .serve(app.into_make_service())
@@ -139,64 +243,606 @@ fn main() -> Result<()> {
 
 type SharedState = Arc<State>;
 
+/// Response header reporting if search results came from the cache
+const CACHE_HEADER: &str = "x-cache";
+/// Response header describing what was done to the query signature to search it,
+/// for CSV responses (including gather). JSON responses include it as `query.adjustments`.
+const ADJUSTMENTS_HEADER: &str = "x-query-adjustments";
+
+/// Largest request body for a single signature, in bytes
+const MAX_SIG_BYTES: u64 = 1024 * 5_000; // ~5mb
+/// Largest request body for raw hashes, in bytes
+const MAX_HASHES_BYTES: u64 = 1024 * 50_000; // ~50mb
+/// Largest request body for sequence uploads and batches, in bytes
+const MAX_UPLOAD_BYTES: u64 = 1024 * 100_000; // ~100mb
+
+/// Response formats supported by the search endpoints.
+enum OutputFormat {
+    Csv,
+    Json,
+}
+
+impl OutputFormat {
+    /// Pick a format based on the `Accept` header. Defaults to CSV.
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let accept = headers
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+
+        if accept
+            .split(',')
+            .any(|media| media.split(';').next().unwrap_or_default().trim() == "application/json")
+        {
+            OutputFormat::Json
+        } else {
+            OutputFormat::Csv
+        }
+    }
+}
+
+/// Search parameters clients can set in the query string.
+#[derive(Deserialize, Debug, Default)]
+struct SearchParams {
+    threshold_bp: Option<usize>,
+    min_containment: Option<f64>,
+    max_results: Option<usize>,
+    /// Comma-separated metadata columns to include in the results
+    columns: Option<String>,
+    /// Keep k-mer abundances when sketching sequences on the server
+    #[serde(default)]
+    abundance: bool,
+}
+
+/// Parameters for `/search/hashes`: how the hashes were computed,
+/// an optional query name and search parameters.
+#[derive(Deserialize, Debug)]
+struct HashesParams {
+    scaled: u64,
+    ksize: Option<u32>,
+    name: Option<String>,
+    threshold_bp: Option<usize>,
+    min_containment: Option<f64>,
+    max_results: Option<usize>,
+    columns: Option<String>,
+}
+
+impl HashesParams {
+    fn search_params(&self) -> SearchParams {
+        SearchParams {
+            threshold_bp: self.threshold_bp,
+            min_containment: self.min_containment,
+            max_results: self.max_results,
+            columns: self.columns.clone(),
+            abundance: false,
+        }
+    }
+}
+
+/// Parameters for `/stream/search`: search parameters, an optional query name
+/// and whether to keep abundances.
+#[derive(Deserialize, Debug, Default)]
+struct StreamParams {
+    name: Option<String>,
+    #[serde(default)]
+    abundance: bool,
+    threshold_bp: Option<usize>,
+    min_containment: Option<f64>,
+    max_results: Option<usize>,
+    columns: Option<String>,
+}
+
+impl StreamParams {
+    fn search_params(&self) -> SearchParams {
+        SearchParams {
+            threshold_bp: self.threshold_bp,
+            min_containment: self.min_containment,
+            max_results: self.max_results,
+            columns: self.columns.clone(),
+            abundance: false,
+        }
+    }
+}
+
+/// Parameters for `/gather`: metadata columns to include in the results.
+#[derive(Deserialize, Debug, Default)]
+struct GatherParams {
+    columns: Option<String>,
+}
+
+/// Parameters for `/explain`: the dataset to compare the query with.
+#[derive(Deserialize, Debug)]
+struct ExplainParams {
+    dataset: String,
+}
+
+/// Path of `/hash[/{index}]/{value}`. The default index is used if not given.
+#[derive(Deserialize, Debug)]
+struct LookupPath {
+    index: Option<String>,
+    value: String,
+}
+
+/// Parameters for `POST /jobs`: the job kind, index and search parameters.
+#[derive(Deserialize, Debug, Default)]
+struct JobParams {
+    #[serde(default)]
+    kind: JobKind,
+    index: Option<String>,
+    threshold_bp: Option<usize>,
+    min_containment: Option<f64>,
+    max_results: Option<usize>,
+    columns: Option<String>,
+}
+
+impl JobParams {
+    /// Parameters set that the job kind doesn't use
+    fn unused_params(&self) -> Vec<&'static str> {
+        if self.kind != JobKind::Gather {
+            return vec![];
+        }
+        [
+            ("threshold_bp", self.threshold_bp.is_some()),
+            ("min_containment", self.min_containment.is_some()),
+            ("max_results", self.max_results.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(name, set)| set.then_some(name))
+        .collect()
+    }
+
+    fn search_params(&self) -> SearchParams {
+        SearchParams {
+            threshold_bp: self.threshold_bp,
+            min_containment: self.min_containment,
+            max_results: self.max_results,
+            columns: self.columns.clone(),
+            abundance: false,
+        }
+    }
+}
+
 struct State {
-    db: Arc<RevIndex>,
-    template: Arc<Sketch>,
-    threshold: usize,
+    indexes: Vec<Arc<Index>>,
+    limits: Limits,
+    request_timeout: Duration,
+    /// Searches from a batch running at the same time, like requests
+    max_concurrent_searches: usize,
+    ready_timeout: Duration,
+    jobs: Arc<Jobs>,
+    metrics: Arc<Metrics>,
 }
 
 impl State {
-    async fn search(&self, query: Signature) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-        let db = self.db.clone();
-        let threshold = self.threshold;
-        let template = self.template.clone();
-
-        let Ok((matches, query_size)) = tokio::task::spawn_blocking(move || {
-            if let Some(Sketch::MinHash(mh)) = query.select_sketch(&template) {
-                let counter = db.counter_for_query(mh);
-                let matches = db.matches_from_counter(counter, threshold);
-                Ok((matches, mh.size() as f64))
-            } else {
-                Err("Could not extract compatible sketch to compare")
+    /// Find an index by name, or the default (first) index if no name is given.
+    fn index(&self, name: Option<&str>) -> Result<Arc<Index>, String> {
+        match name {
+            None => Ok(self.indexes[0].clone()),
+            Some(name) => self
+                .indexes
+                .iter()
+                .find(|idx| idx.name() == name)
+                .cloned()
+                .ok_or_else(|| format!("Index '{name}' not found")),
+        }
+    }
+
+    fn search_options(
+        &self,
+        index: &Index,
+        params: &SearchParams,
+    ) -> Result<SearchOptions, String> {
+        let scaled = index.scaled();
+
+        let threshold = match params.threshold_bp {
+            Some(bp) if bp < self.limits.min_threshold_bp => {
+                return Err(format!(
+                    "threshold_bp must be at least {}",
+                    self.limits.min_threshold_bp
+                ))
             }
+            Some(bp) => bp / scaled,
+            None => index.threshold(),
+        };
+
+        let min_containment = params.min_containment.unwrap_or(0.0);
+        if !(0.0..=1.0).contains(&min_containment) {
+            return Err("min_containment must be between 0 and 1".into());
+        }
+
+        let max_results = match params.max_results {
+            Some(n) if n > self.limits.max_results => {
+                return Err(format!(
+                    "max_results must be at most {}",
+                    self.limits.max_results
+                ))
+            }
+            Some(n) => n,
+            None => self.limits.max_results,
+        };
+
+        let columns = self.columns(index, params.columns.as_deref())?;
+
+        Ok(SearchOptions {
+            threshold,
+            min_containment,
+            max_results,
+            columns,
         })
-        .await?
-        else {
-            return Err("Could not extract compatible sketch to compare".into());
+    }
+
+    /// Parse a comma-separated list of metadata columns, checking the index has them.
+    fn columns(&self, index: &Index, columns: Option<&str>) -> Result<Vec<String>, String> {
+        let columns: Vec<String> = columns
+            .unwrap_or_default()
+            .split(',')
+            .map(|c| c.trim())
+            .filter(|c| !c.is_empty())
+            .map(|c| c.into())
+            .collect();
+        index.check_columns(&columns)?;
+        Ok(columns)
+    }
+}
+
+async fn search(
+    ContentLengthLimit(bytes): ContentLengthLimit<Bytes, MAX_SIG_BYTES>,
+    Extension(state): Extension<SharedState>,
+    index: Option<Path<String>>,
+    Query(params): Query<SearchParams>,
+    headers: HeaderMap,
+) -> Response<BoxBody> {
+    let index = match state.index(index.as_ref().map(|Path(name)| name.as_str())) {
+        Ok(index) => index,
+        Err(e) => return (StatusCode::NOT_FOUND, e).into_response(),
+    };
+
+    let options = match state.search_options(&index, &params) {
+        Ok(options) => options,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+
+    let sig = match index.parse_sig(&bytes) {
+        Ok(sig) => sig,
+        Err(e) => {
+            return {
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!("Error parsing signature: {e}"),
+                )
+                    .into_response()
+            }
+        }
+    };
+
+    search_response(&index, sig, options, &headers).await
+}
+
+/// Search FracMinHash hashes directly, sent as a JSON array (with `Content-Type: application/json`)
+/// or as little-endian u64 values.
+async fn search_hashes(
+    ContentLengthLimit(bytes): ContentLengthLimit<Bytes, MAX_HASHES_BYTES>,
+    Extension(state): Extension<SharedState>,
+    index: Option<Path<String>>,
+    Query(params): Query<HashesParams>,
+    headers: HeaderMap,
+) -> Response<BoxBody> {
+    let index = match state.index(index.as_ref().map(|Path(name)| name.as_str())) {
+        Ok(index) => index,
+        Err(e) => return (StatusCode::NOT_FOUND, e).into_response(),
+    };
+
+    let options = match state.search_options(&index, &params.search_params()) {
+        Ok(options) => options,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+
+    let json = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|ct| ct.starts_with("application/json"));
+
+    let sig = Index::parse_hashes(&bytes, json).and_then(|hashes| {
+        index.query_from_hashes(
+            params.name.clone().unwrap_or_else(|| "hashes".into()),
+            &hashes,
+            params.scaled,
+            params.ksize,
+        )
+    });
+    let sig = match sig {
+        Ok(sig) => sig,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("Error parsing hashes: {e}"),
+            )
+                .into_response()
+        }
+    };
+
+    search_response(&index, sig, options, &headers).await
+}
+
+/// Search a query and format the results as requested by the client.
+async fn search_response(
+    index: &Index,
+    sig: Signature,
+    options: SearchOptions,
+    headers: &HeaderMap,
+) -> Response<BoxBody> {
+    match index.search(sig, options, Default::default()).await {
+        Ok(results) => {
+            let cache_status = [(CACHE_HEADER, if results.cached() { "HIT" } else { "MISS" })];
+            match OutputFormat::from_headers(headers) {
+                OutputFormat::Json => (StatusCode::OK, cache_status, Json(results)).into_response(),
+                OutputFormat::Csv => (
+                    StatusCode::OK,
+                    cache_status,
+                    [(ADJUSTMENTS_HEADER, results.adjustments().join("; "))],
+                    [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+                    results.to_csv().join("\n"),
+                )
+                    .into_response(),
+            }
+        }
+        Err(e) => {
+            tracing::error!(index = index.name(), "Error processing query: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Something went wrong: {e}"),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Sketch FASTA/FASTQ files (possibly gzipped) uploaded as multipart/form-data, and search them.
+///
+/// All files are sketched into one query. An optional `name` field sets the query name,
+/// otherwise it is named after the first file.
+async fn search_sequences(
+    ContentLengthLimit(mut multipart): ContentLengthLimit<Multipart, MAX_UPLOAD_BYTES>,
+    Extension(state): Extension<SharedState>,
+    index: Option<Path<String>>,
+    Query(params): Query<SearchParams>,
+    headers: HeaderMap,
+) -> Response<BoxBody> {
+    let index = match state.index(index.as_ref().map(|Path(name)| name.as_str())) {
+        Ok(index) => index,
+        Err(e) => return (StatusCode::NOT_FOUND, e).into_response(),
+    };
+
+    let options = match state.search_options(&index, &params) {
+        Ok(options) => options,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+
+    let mut name = None;
+    let mut file_name = None;
+    let mut inputs = vec![];
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    format!("Error reading upload: {e}"),
+                )
+                    .into_response()
+            }
         };
 
-        let mut csv = vec!["SRA accession,containment".into()];
-        csv.extend(matches.into_iter().map(|(path, size)| {
-            let containment = size as f64 / query_size;
-            format!(
-                "{},{}",
-                path.split('/').last().unwrap().split('.').next().unwrap(),
-                containment
+        let is_name = field.name() == Some("name");
+        if file_name.is_none() {
+            file_name = field.file_name().map(|f| f.to_string());
+        }
+        match field.bytes().await {
+            Ok(data) if is_name => name = Some(String::from_utf8_lossy(&data).to_string()),
+            Ok(data) => inputs.push(std::io::Cursor::new(data)),
+            Err(e) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    format!("Error reading upload: {e}"),
+                )
+                    .into_response()
+            }
+        }
+    }
+
+    if inputs.is_empty() {
+        return (StatusCode::BAD_REQUEST, "No sequence files uploaded").into_response();
+    }
+
+    let sig = match index
+        .sketch(name.or(file_name), inputs, params.abundance)
+        .await
+    {
+        Ok(sig) => sig,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("Error sketching sequences: {e}"),
             )
-        }));
-        Ok(csv)
+                .into_response()
+        }
+    };
+
+    search_response(&index, sig, options, &headers).await
+}
+
+/// Sketch a FASTA/FASTQ file (possibly gzipped) sent as the raw request body, and search it.
+///
+/// The body is sketched as it arrives, so there is no size limit and memory use
+/// depends only on the sketch size.
+async fn search_stream(
+    RawBody(mut body): RawBody,
+    Extension(state): Extension<SharedState>,
+    index: Option<Path<String>>,
+    Query(params): Query<StreamParams>,
+    headers: HeaderMap,
+) -> Response<BoxBody> {
+    let index = match state.index(index.as_ref().map(|Path(name)| name.as_str())) {
+        Ok(index) => index,
+        Err(e) => return (StatusCode::NOT_FOUND, e).into_response(),
+    };
+
+    let options = match state.search_options(&index, &params.search_params()) {
+        Ok(options) => options,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+
+    // A few chunks in flight, so reading the body and sketching can overlap
+    let (tx, rx) = tokio::sync::mpsc::channel(16);
+    let sketch = index.sketch(
+        params.name.clone(),
+        vec![ChunkReader::new(rx)],
+        params.abundance,
+    );
+    let upload = async move {
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(|e| std::io::Error::other(e.to_string()));
+            // The sketcher stopped early, so the rest of the body is not needed
+            if tx.send(chunk).await.is_err() {
+                break;
+            }
+        }
+    };
+
+    let (sig, _) = tokio::join!(sketch, upload);
+    let sig = match sig {
+        Ok(sig) => sig,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("Error sketching sequences: {e}"),
+            )
+                .into_response()
+        }
+    };
+
+    search_response(&index, sig, options, &headers).await
+}
+
+/// Search many queries at once, from a JSON file with multiple signatures
+/// or a sourmash zip collection. Results are always JSON, in the same order as the queries.
+async fn search_batch(
+    ContentLengthLimit(bytes): ContentLengthLimit<Bytes, MAX_UPLOAD_BYTES>,
+    Extension(state): Extension<SharedState>,
+    index: Option<Path<String>>,
+    Query(params): Query<SearchParams>,
+) -> Response<BoxBody> {
+    let index = match state.index(index.as_ref().map(|Path(name)| name.as_str())) {
+        Ok(index) => index,
+        Err(e) => return (StatusCode::NOT_FOUND, e).into_response(),
+    };
+
+    let options = match state.search_options(&index, &params) {
+        Ok(options) => options,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+
+    let sigs = match Index::parse_sigs(&bytes) {
+        Ok(sigs) => sigs,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("Error parsing signatures: {e}"),
+            )
+                .into_response()
+        }
+    };
+
+    if sigs.len() > state.limits.max_batch_size {
+        return (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!(
+                "Too many queries: {}, at most {} are allowed",
+                sigs.len(),
+                state.limits.max_batch_size
+            ),
+        )
+            .into_response();
     }
 
-    fn parse_sig(&self, raw_data: &[u8]) -> Result<Signature, BoxError> {
-        let sig = Signature::from_reader(raw_data)?.swap_remove(0);
-        if sig.select_sketch(&self.template).is_none() {
-            Err(format!(
-                "Could not extract compatible sketch to compare. Expected k={}",
-                &self.template.ksize(),
+    (
+        StatusCode::OK,
+        Json(
+            index
+                .search_batch(sigs, options, state.max_concurrent_searches)
+                .await,
+        ),
+    )
+        .into_response()
+}
+
+/// Report the hashes a query shares with a dataset, given by name or accession.
+async fn explain(
+    ContentLengthLimit(bytes): ContentLengthLimit<Bytes, MAX_SIG_BYTES>,
+    Extension(state): Extension<SharedState>,
+    index: Option<Path<String>>,
+    Query(params): Query<ExplainParams>,
+) -> Response<BoxBody> {
+    let index = match state.index(index.as_ref().map(|Path(name)| name.as_str())) {
+        Ok(index) => index,
+        Err(e) => return (StatusCode::NOT_FOUND, e).into_response(),
+    };
+
+    let sig = match index.parse_sig(&bytes) {
+        Ok(sig) => sig,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("Error parsing signature: {e}"),
             )
-            .into())
-        } else {
-            Ok(sig)
+                .into_response()
+        }
+    };
+
+    match index.explain(sig, params.dataset).await {
+        Ok(results) => (StatusCode::OK, Json(results)).into_response(),
+        Err(e)
+            if e.is::<ExplainError>()
+                && !matches!(
+                    e.downcast_ref(),
+                    Some(ExplainError::Index(_) | ExplainError::MissingKsize(..))
+                ) =>
+        {
+            let status = match e.downcast_ref::<ExplainError>() {
+                Some(ExplainError::NotFound(_)) => StatusCode::NOT_FOUND,
+                _ => StatusCode::BAD_REQUEST,
+            };
+            (status, e.to_string()).into_response()
+        }
+        Err(e) => {
+            tracing::error!(index = index.name(), "Error processing query: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Something went wrong: {e}"),
+            )
+                .into_response()
         }
     }
 }
 
-async fn search(
-    ContentLengthLimit(bytes): ContentLengthLimit<Bytes, { 1024 * 5_000 }>, // ~5mb
+async fn gather(
+    ContentLengthLimit(bytes): ContentLengthLimit<Bytes, MAX_SIG_BYTES>,
     Extension(state): Extension<SharedState>,
-    //) -> Result<Json<serde_json::Value>, StatusCode> {
+    index: Option<Path<String>>,
+    Query(params): Query<GatherParams>,
 ) -> Response<BoxBody> {
-    let sig = match state.parse_sig(&bytes) {
+    let index = match state.index(index.as_ref().map(|Path(name)| name.as_str())) {
+        Ok(index) => index,
+        Err(e) => return (StatusCode::NOT_FOUND, e).into_response(),
+    };
+
+    let columns = match state.columns(&index, params.columns.as_deref()) {
+        Ok(columns) => columns,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+
+    let sig = match index.parse_sig(&bytes) {
         Ok(sig) => sig,
         Err(e) => {
             return {
@@ -209,25 +855,327 @@ async fn search(
         }
     };
 
-    match state.search(sig).await {
-        Ok(matches) => (
+    match index.gather(sig, &columns, Default::default()).await {
+        Ok(results) => (
             StatusCode::OK,
+            [(ADJUSTMENTS_HEADER, results.adjustments().join("; "))],
             [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
-            matches.join("\n"),
+            results.to_csv().join("\n"),
         )
             .into_response(),
-        Err(e) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Something went wrong: {e}"),
+        Err(e) => {
+            tracing::error!(index = index.name(), "Error processing query: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Something went wrong: {e}"),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Queue a search or gather to run in the background.
+/// Status and results are available at the returned location.
+async fn submit_job(
+    ContentLengthLimit(bytes): ContentLengthLimit<Bytes, MAX_SIG_BYTES>,
+    Extension(state): Extension<SharedState>,
+    Query(params): Query<JobParams>,
+) -> Response<BoxBody> {
+    let unused = params.unused_params();
+    if !unused.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("Gather jobs don't accept {}", unused.join(", ")),
+        )
+            .into_response();
+    }
+
+    let index = match state.index(params.index.as_deref()) {
+        Ok(index) => index,
+        Err(e) => return (StatusCode::NOT_FOUND, e).into_response(),
+    };
+
+    let options = match state.search_options(&index, &params.search_params()) {
+        Ok(options) => options,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+
+    let sig = match index.parse_sig(&bytes) {
+        Ok(sig) => sig,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("Error parsing signature: {e}"),
+            )
+                .into_response()
+        }
+    };
+
+    match state.jobs.submit(params.kind, index, sig, options) {
+        Ok(id) => {
+            let location = format!("/jobs/{id}");
+            (
+                StatusCode::ACCEPTED,
+                [(header::LOCATION, location)],
+                Json(state.jobs.info(&id)),
+            )
+                .into_response()
+        }
+        Err(e) => (StatusCode::SERVICE_UNAVAILABLE, e).into_response(),
+    }
+}
+
+async fn job_status(
+    Extension(state): Extension<SharedState>,
+    Path(id): Path<String>,
+) -> Response<BoxBody> {
+    match state.jobs.info(&id) {
+        Some(info) => (StatusCode::OK, Json(info)).into_response(),
+        None => (StatusCode::NOT_FOUND, format!("Job '{id}' not found")).into_response(),
+    }
+}
+
+/// Results of a finished job, in the same format as the synchronous endpoints.
+async fn job_result(
+    Extension(state): Extension<SharedState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Response<BoxBody> {
+    let output = match state.jobs.result(&id) {
+        Ok(output) => output,
+        Err(ResultError::NotFound) => {
+            return (StatusCode::NOT_FOUND, format!("Job '{id}' not found")).into_response()
+        }
+        Err(ResultError::Pending(status)) => {
+            return (
+                StatusCode::CONFLICT,
+                format!("Job '{id}' is not finished yet: {status:?}"),
+            )
+                .into_response()
+        }
+        Err(ResultError::Failed(e)) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Something went wrong: {e}"),
+            )
+                .into_response()
+        }
+    };
+
+    match (output.as_ref(), OutputFormat::from_headers(&headers)) {
+        (JobOutput::Search(results), OutputFormat::Json) => {
+            (StatusCode::OK, Json(results)).into_response()
+        }
+        (JobOutput::Search(results), OutputFormat::Csv) => (
+            StatusCode::OK,
+            [(ADJUSTMENTS_HEADER, results.adjustments().join("; "))],
+            [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+            results.to_csv().join("\n"),
+        )
+            .into_response(),
+        (JobOutput::Gather(results), _) => (
+            StatusCode::OK,
+            [(ADJUSTMENTS_HEADER, results.adjustments().join("; "))],
+            [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+            results.to_csv().join("\n"),
         )
             .into_response(),
     }
 }
 
+/// Datasets containing a hash, or each of a comma-separated list of hashes.
+async fn lookup_hashes(
+    Extension(state): Extension<SharedState>,
+    Path(LookupPath { index, value }): Path<LookupPath>,
+) -> Response<BoxBody> {
+    let index = match state.index(index.as_deref()) {
+        Ok(index) => index,
+        Err(e) => return (StatusCode::NOT_FOUND, e).into_response(),
+    };
+
+    let hashes = match value
+        .split(',')
+        .map(|h| h.trim().parse::<u64>())
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(hashes) => hashes,
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("Invalid hash: {e}")).into_response(),
+    };
+
+    if hashes.len() > state.limits.max_batch_size {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Too many hashes: {}, at most {} are allowed",
+                hashes.len(),
+                state.limits.max_batch_size
+            ),
+        )
+            .into_response();
+    }
+
+    match index.lookup(hashes).await {
+        Ok(results) => (StatusCode::OK, Json(results)).into_response(),
+        Err(e) if e.is::<LookupError>() => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+        Err(e) => {
+            tracing::error!(index = index.name(), "Error looking up hashes: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Something went wrong: {e}"),
+            )
+                .into_response()
+        }
+    }
+}
+
+async fn list_indexes(Extension(state): Extension<SharedState>) -> Response<BoxBody> {
+    let info: Vec<_> = state
+        .indexes
+        .iter()
+        .enumerate()
+        .map(|(i, idx)| idx.info(i == 0))
+        .collect();
+
+    (StatusCode::OK, Json(info)).into_response()
+}
+
+/// An endpoint as described by `/info`.
+#[derive(Serialize)]
+struct EndpointInfo {
+    method: &'static str,
+    /// Path, with optional segments in brackets
+    path: &'static str,
+    /// Output formats, picked with the `Accept` header if more than one
+    formats: &'static [&'static str],
+    /// Largest request body accepted, if limited
+    max_body_bytes: Option<u64>,
+}
+
+/// Server parameters and capabilities, as reported by `/info`.
+#[derive(Serialize)]
+struct ServerInfo {
+    version: &'static str,
+    indexes: Vec<IndexInfo>,
+    endpoints: Vec<EndpointInfo>,
+    limits: Limits,
+    request_timeout_secs: u64,
+}
+
+const ENDPOINTS: &[(&str, &str, &[&str], Option<u64>)] = &[
+    (
+        "POST",
+        "/search[/{index}]",
+        &["csv", "json"],
+        Some(MAX_SIG_BYTES),
+    ),
+    (
+        "POST",
+        "/search/hashes[/{index}]",
+        &["csv", "json"],
+        Some(MAX_HASHES_BYTES),
+    ),
+    (
+        "POST",
+        "/sequences/search[/{index}]",
+        &["csv", "json"],
+        Some(MAX_UPLOAD_BYTES),
+    ),
+    ("POST", "/stream/search[/{index}]", &["csv", "json"], None),
+    (
+        "POST",
+        "/batch/search[/{index}]",
+        &["json"],
+        Some(MAX_UPLOAD_BYTES),
+    ),
+    ("POST", "/explain[/{index}]", &["json"], Some(MAX_SIG_BYTES)),
+    ("POST", "/gather[/{index}]", &["csv"], Some(MAX_SIG_BYTES)),
+    ("POST", "/jobs", &["json"], Some(MAX_SIG_BYTES)),
+    ("GET", "/jobs/{id}", &["json"], None),
+    ("GET", "/jobs/{id}/result", &["csv", "json"], None),
+    ("GET", "/hash[/{index}]/{value}", &["json"], None),
+    ("GET", "/indexes", &["json"], None),
+    ("GET", "/info", &["json"], None),
+    ("GET", "/health", &["text"], None),
+    ("GET", "/ready", &["json"], None),
+    ("GET", "/metrics", &["prometheus"], None),
+];
+
+/// Describe the served indexes, endpoints and limits, so clients can
+/// prepare compatible queries.
+async fn server_info(Extension(state): Extension<SharedState>) -> Response<BoxBody> {
+    let info = ServerInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        indexes: state
+            .indexes
+            .iter()
+            .enumerate()
+            .map(|(i, idx)| idx.info(i == 0))
+            .collect(),
+        endpoints: ENDPOINTS
+            .iter()
+            .map(|&(method, path, formats, max_body_bytes)| EndpointInfo {
+                method,
+                path,
+                formats,
+                max_body_bytes,
+            })
+            .collect(),
+        limits: state.limits.clone(),
+        request_timeout_secs: state.request_timeout.as_secs(),
+    };
+
+    (StatusCode::OK, Json(info)).into_response()
+}
+
 async fn health() -> Response<BoxBody> {
     (StatusCode::OK, "I'm doing science and I'm still alive").into_response()
 }
 
+/// Readiness status of the server, as reported by `/ready`.
+#[derive(Serialize)]
+struct ReadyStatus {
+    ready: bool,
+    version: &'static str,
+    indexes: Vec<Readiness>,
+}
+
+/// Unlike `/health`, only succeeds if all indexes can answer queries.
+async fn ready(Extension(state): Extension<SharedState>) -> Response<BoxBody> {
+    let mut indexes = Vec::with_capacity(state.indexes.len());
+    for index in &state.indexes {
+        indexes.push(index.probe(state.ready_timeout).await);
+    }
+
+    let status = ReadyStatus {
+        ready: indexes.iter().all(|idx| idx.is_ready()),
+        version: env!("CARGO_PKG_VERSION"),
+        indexes,
+    };
+
+    if status.ready {
+        (StatusCode::OK, Json(status)).into_response()
+    } else {
+        tracing::warn!("Readiness check failed");
+        (StatusCode::SERVICE_UNAVAILABLE, Json(status)).into_response()
+    }
+}
+
+async fn export_metrics(Extension(state): Extension<SharedState>) -> Response<BoxBody> {
+    match state.metrics.render(&state.indexes).await {
+        Ok(metrics) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+            metrics,
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Error exporting metrics: {e}"),
+        )
+            .into_response(),
+    }
+}
+
 async fn handle_static_serve_error(error: std::io::Error) -> impl IntoResponse {
     (
         StatusCode::INTERNAL_SERVER_ERROR,
@@ -235,20 +1183,120 @@ async fn handle_static_serve_error(error: std::io::Error) -> impl IntoResponse {
     )
 }
 
-async fn handle_error(error: BoxError) -> impl IntoResponse {
+async fn handle_error(error: BoxError, metrics: Arc<Metrics>) -> impl IntoResponse {
     if error.is::<tower::timeout::error::Elapsed>() {
         return (StatusCode::REQUEST_TIMEOUT, Cow::from("request timed out"));
     }
 
     if error.is::<tower::load_shed::error::Overloaded>() {
+        metrics.load_shed();
         return (
             StatusCode::SERVICE_UNAVAILABLE,
             Cow::from("service is overloaded, try again later"),
         );
     }
 
+    tracing::error!("Unhandled internal error: {}", error);
     (
         StatusCode::INTERNAL_SERVER_ERROR,
         Cow::from(format!("Unhandled internal error: {}", error)),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_from_args(args: &[&str]) -> Result<Config> {
+        let args = std::iter::once("mastiff-server").chain(args.iter().copied());
+        Cli::try_parse_from(args)?.into_config()
+    }
+
+    #[test]
+    fn cli_without_config_file() {
+        let config = config_from_args(&["sra=sra.rocksdb", "-k", "31", "-p", "8080"]).unwrap();
+        assert_eq!(config.indexes[0].name, "sra");
+        assert_eq!(config.defaults.ksize, 31);
+        assert_eq!(config.defaults.scaled, 1000);
+        assert_eq!(config.port, 8080);
+    }
+
+    #[test]
+    fn cli_overrides_config_file() {
+        let path = std::env::temp_dir().join(format!("mastiff-config-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+            port = 8080
+
+            [defaults]
+            scaled = 10000
+            threshold_bp = 100000
+
+            [limits]
+            max_results = 10
+
+            [[indexes]]
+            name = "gtdb"
+            path = "gtdb.rocksdb"
+            "#,
+        )
+        .unwrap();
+        let config_path = path.to_string_lossy();
+
+        let config = config_from_args(&["-c", &config_path]).unwrap();
+        assert_eq!(config.indexes[0].name, "gtdb");
+        assert_eq!(config.port, 8080);
+        assert_eq!(config.defaults.scaled, 10000);
+
+        let config = config_from_args(&[
+            "-c",
+            &config_path,
+            "sra=sra.rocksdb",
+            "--threshold_bp",
+            "20000",
+            "--max_results",
+            "100",
+        ])
+        .unwrap();
+        assert_eq!(config.indexes.len(), 1);
+        assert_eq!(config.indexes[0].name, "sra");
+        assert_eq!(config.port, 8080);
+        assert_eq!(config.defaults.scaled, 10000);
+        assert_eq!(config.defaults.threshold_bp, 20000);
+        assert_eq!(config.limits.max_results, 100);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn cli_config_is_validated() {
+        assert!(config_from_args(&[]).is_err());
+        assert!(config_from_args(&["sra=sra.rocksdb", "--threshold_bp", "1000"]).is_err());
+        assert!(config_from_args(&[
+            "sra=sra.rocksdb",
+            "--threshold_bp",
+            "1000",
+            "--min_threshold_bp",
+            "1000"
+        ])
+        .is_ok());
+    }
+
+    #[test]
+    fn gather_job_params() {
+        let params = JobParams {
+            kind: JobKind::Gather,
+            threshold_bp: Some(10000),
+            max_results: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(params.unused_params(), vec!["threshold_bp", "max_results"]);
+
+        let params = JobParams {
+            kind: JobKind::Search,
+            ..params
+        };
+        assert!(params.unused_params().is_empty());
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use tower::BoxError;
+
+/// Metadata for the datasets in an index, loaded from a CSV file keyed by accession
+/// (like the SRA runinfo tables used to build the indexes).
+///
+/// The whole table is kept in memory, so `columns` can be used to load only
+/// the columns clients need. If an accession is in several rows the last one is used.
+pub struct Metadata {
+    columns: Vec<String>,
+    rows: HashMap<String, Vec<String>>,
+}
+
+impl Metadata {
+    pub fn from_path(path: &Path, key: &str, columns: Option<&[String]>) -> Result<Self, BoxError> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .flexible(true)
+            .from_path(path)
+            .map_err(|e| format!("Error opening {}: {e}", path.display()))?;
+
+        let headers = rdr.headers()?.clone();
+        let key_pos = headers
+            .iter()
+            .position(|h| h == key)
+            .ok_or_else(|| format!("Column '{key}' not found in {}", path.display()))?;
+
+        let mut keep = vec![];
+        match columns {
+            Some(columns) => {
+                for column in columns {
+                    let pos = headers.iter().position(|h| h == column).ok_or_else(|| {
+                        format!("Column '{column}' not found in {}", path.display())
+                    })?;
+                    keep.push(pos);
+                }
+            }
+            None => keep.extend((0..headers.len()).filter(|&pos| pos != key_pos)),
+        }
+
+        let mut rows = HashMap::new();
+        for record in rdr.records() {
+            let record = record?;
+            let Some(accession) = record.get(key_pos) else {
+                continue;
+            };
+            // Concatenated runinfo files repeat the header
+            if accession.is_empty() || accession == key {
+                continue;
+            }
+            let values = keep
+                .iter()
+                .map(|&pos| record.get(pos).unwrap_or_default().to_string())
+                .collect();
+            rows.insert(accession.to_string(), values);
+        }
+
+        Ok(Metadata {
+            columns: keep.iter().map(|&pos| headers[pos].to_string()).collect(),
+            rows,
+        })
+    }
+
+    /// Columns available to clients.
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    /// Number of datasets with metadata.
+    pub fn datasets(&self) -> usize {
+        self.rows.len()
+    }
+
+    /// Check all requested columns are available.
+    pub fn check_columns(&self, columns: &[String]) -> Result<(), String> {
+        match columns.iter().find(|c| !self.columns.contains(c)) {
+            Some(column) => Err(format!(
+                "Unknown metadata column '{column}'. Available columns: {}",
+                self.columns.join(", ")
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Values of `columns` for a dataset. Missing datasets or values are empty.
+    pub fn select(&self, accession: &str, columns: &[String]) -> BTreeMap<String, String> {
+        let row = self.rows.get(accession);
+        columns
+            .iter()
+            .map(|column| {
+                let value = row
+                    .and_then(|row| {
+                        let pos = self.columns.iter().position(|c| c == column)?;
+                        row.get(pos).cloned()
+                    })
+                    .unwrap_or_default();
+                (column.clone(), value)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RUNINFO: &str = "\
+Run,ReleaseDate,ScientificName,bases
+SRR123456,2019-01-01,Homo sapiens,1000
+SRR654321,2020-01-01,Mus musculus,2000
+Run,ReleaseDate,ScientificName,bases
+,2021-01-01,Missing accession,3000
+SRR123456,2022-01-01,Homo sapiens,4000
+";
+
+    fn runinfo(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "mastiff-metadata-{}-{name}.csv",
+            std::process::id()
+        ));
+        std::fs::write(&path, RUNINFO).unwrap();
+        path
+    }
+
+    fn columns(columns: &[&str]) -> Vec<String> {
+        columns.iter().map(|c| c.to_string()).collect()
+    }
+
+    #[test]
+    fn all_columns() {
+        let path = runinfo("all");
+        let metadata = Metadata::from_path(&path, "Run", None).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            metadata.columns(),
+            ["ReleaseDate", "ScientificName", "bases"]
+        );
+        // Repeated headers and rows without accession are skipped
+        assert_eq!(metadata.datasets(), 2);
+    }
+
+    #[test]
+    fn unknown_columns() {
+        let path = runinfo("unknown");
+        let unknown_key = Metadata::from_path(&path, "Accession", None);
+        let unknown_column = Metadata::from_path(&path, "Run", Some(&columns(&["Platform"])));
+        let metadata = Metadata::from_path(&path, "Run", Some(&columns(&["bases"]))).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(unknown_key
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("Column 'Accession' not found"));
+        assert!(unknown_column
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("Column 'Platform' not found"));
+
+        assert_eq!(metadata.columns(), ["bases"]);
+        assert!(metadata.check_columns(&columns(&["bases"])).is_ok());
+        let err = metadata
+            .check_columns(&columns(&["bases", "ScientificName"]))
+            .unwrap_err();
+        assert_eq!(
+            err,
+            "Unknown metadata column 'ScientificName'. Available columns: bases"
+        );
+    }
+
+    #[test]
+    fn missing_accessions() {
+        let path = runinfo("missing");
+        let metadata = Metadata::from_path(&path, "Run", None).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let selected = metadata.select("SRR000000", &columns(&["ScientificName", "bases"]));
+        assert_eq!(selected.len(), 2);
+        assert!(selected.values().all(|v| v.is_empty()));
+        assert!(metadata.select("", &columns(&["bases"]))["bases"].is_empty());
+    }
+
+    #[test]
+    fn duplicate_rows_keep_last() {
+        let path = runinfo("duplicate");
+        let metadata = Metadata::from_path(&path, "Run", None).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let selected = metadata.select("SRR123456", &columns(&["ReleaseDate", "bases"]));
+        assert_eq!(selected["ReleaseDate"], "2022-01-01");
+        assert_eq!(selected["bases"], "4000");
+        assert_eq!(
+            metadata.select("SRR654321", &columns(&["ScientificName"]))["ScientificName"],
+            "Mus musculus"
+        );
+    }
+}
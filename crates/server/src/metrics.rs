@@ -0,0 +1,267 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+
+use axum::{
+    extract::MatchedPath,
+    http::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use prometheus::{
+    exponential_buckets, histogram_opts, opts, Encoder, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, IntGaugeVec, Registry, TextEncoder,
+};
+use tokio::task::JoinError;
+
+use crate::index::Index;
+
+/// Prometheus metrics for the server, exported by `/metrics`.
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    query_size: HistogramVec,
+    query_matches: HistogramVec,
+    stage_duration: HistogramVec,
+    blocking_queued: IntGauge,
+    blocking_running: IntGauge,
+    load_shed: IntCounter,
+    index_disk_bytes: IntGaugeVec,
+    index_sst_files: IntGaugeVec,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new_custom(Some("mastiff".into()), None)?;
+
+        let requests = IntCounterVec::new(
+            opts!("http_requests_total", "HTTP requests processed"),
+            &["route", "method", "status"],
+        )?;
+        let request_duration = HistogramVec::new(
+            histogram_opts!(
+                "http_request_duration_seconds",
+                "Time to answer HTTP requests",
+                exponential_buckets(0.005, 2.5, 12)?
+            ),
+            &["route", "method"],
+        )?;
+        let query_size = HistogramVec::new(
+            histogram_opts!(
+                "query_size_hashes",
+                "Number of hashes in query sketches",
+                exponential_buckets(10.0, 4.0, 10)?
+            ),
+            &["index", "endpoint"],
+        )?;
+        let query_matches = HistogramVec::new(
+            histogram_opts!(
+                "query_matches",
+                "Number of matches per query",
+                exponential_buckets(1.0, 4.0, 10)?
+            ),
+            &["index", "endpoint"],
+        )?;
+        let stage_duration = HistogramVec::new(
+            histogram_opts!(
+                "query_stage_duration_seconds",
+                "Time spent in each stage of a query",
+                exponential_buckets(0.001, 2.5, 14)?
+            ),
+            &["index", "stage"],
+        )?;
+        let blocking_queued = IntGauge::new(
+            "blocking_tasks_queued",
+            "Queries waiting for a thread in the blocking pool",
+        )?;
+        let blocking_running = IntGauge::new(
+            "blocking_tasks_running",
+            "Queries running in the blocking pool",
+        )?;
+        let load_shed = IntCounter::new(
+            "load_shed_rejections_total",
+            "Requests rejected because the server was at capacity",
+        )?;
+        let index_disk_bytes = IntGaugeVec::new(
+            opts!(
+                "index_disk_bytes",
+                "Size of the files in the RocksDB index directory"
+            ),
+            &["index"],
+        )?;
+        let index_sst_files = IntGaugeVec::new(
+            opts!(
+                "index_sst_files",
+                "Number of SST files in the RocksDB index directory"
+            ),
+            &["index"],
+        )?;
+
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(request_duration.clone()))?;
+        registry.register(Box::new(query_size.clone()))?;
+        registry.register(Box::new(query_matches.clone()))?;
+        registry.register(Box::new(stage_duration.clone()))?;
+        registry.register(Box::new(blocking_queued.clone()))?;
+        registry.register(Box::new(blocking_running.clone()))?;
+        registry.register(Box::new(load_shed.clone()))?;
+        registry.register(Box::new(index_disk_bytes.clone()))?;
+        registry.register(Box::new(index_sst_files.clone()))?;
+
+        Ok(Metrics {
+            registry,
+            requests,
+            request_duration,
+            query_size,
+            query_matches,
+            stage_duration,
+            blocking_queued,
+            blocking_running,
+            load_shed,
+            index_disk_bytes,
+            index_sst_files,
+        })
+    }
+
+    /// Record the size of a query and how many matches it had.
+    pub fn observe_query(&self, index: &str, endpoint: &str, size: usize, matches: usize) {
+        self.query_size
+            .with_label_values(&[index, endpoint])
+            .observe(size as f64);
+        self.query_matches
+            .with_label_values(&[index, endpoint])
+            .observe(matches as f64);
+    }
+
+    /// Run `f`, recording how long it took as a query `stage`.
+    pub fn time_stage<T>(&self, index: &str, stage: &str, f: impl FnOnce() -> T) -> T {
+        let _timer = self
+            .stage_duration
+            .with_label_values(&[index, stage])
+            .start_timer();
+        f()
+    }
+
+    pub fn load_shed(&self) {
+        self.load_shed.inc();
+    }
+
+    /// Like `tokio::task::spawn_blocking`, but tracking how many tasks
+    /// are waiting for a thread and how many are running.
+    pub async fn spawn_blocking<F, R>(self: &Arc<Self>, f: F) -> Result<R, JoinError>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let metrics = self.clone();
+        metrics.blocking_queued.inc();
+        tokio::task::spawn_blocking(move || {
+            metrics.blocking_queued.dec();
+            let _running = GaugeGuard::new(&metrics.blocking_running);
+            f()
+        })
+        .await
+    }
+
+    /// Encode all metrics in the Prometheus text format.
+    ///
+    /// Index stats are collected here, since they are only needed when scraped.
+    /// The RevIndex doesn't give access to the RocksDB properties, so they come
+    /// from the files in the index directory, read in a blocking thread.
+    pub async fn render(&self, indexes: &[Arc<Index>]) -> Result<String, prometheus::Error> {
+        let paths: Vec<_> = indexes
+            .iter()
+            .map(|index| (index.name().to_string(), index.path().to_path_buf()))
+            .collect();
+        let usage = tokio::task::spawn_blocking(move || {
+            paths
+                .into_iter()
+                .map(|(name, path)| (name, disk_usage(&path)))
+                .collect::<Vec<_>>()
+        })
+        .await
+        .map_err(|e| prometheus::Error::Msg(e.to_string()))?;
+
+        for (name, usage) in usage {
+            match usage {
+                Ok((bytes, sst_files)) => {
+                    self.index_disk_bytes
+                        .with_label_values(&[&name])
+                        .set(bytes as i64);
+                    self.index_sst_files
+                        .with_label_values(&[&name])
+                        .set(sst_files as i64);
+                }
+                Err(e) => tracing::warn!(index = name.as_str(), "Error reading index stats: {e}"),
+            }
+        }
+
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        String::from_utf8(buffer).map_err(|e| prometheus::Error::Msg(e.to_string()))
+    }
+}
+
+/// Increments a gauge while alive, so it is decremented even if the task panics.
+struct GaugeGuard<'a>(&'a IntGauge);
+
+impl<'a> GaugeGuard<'a> {
+    fn new(gauge: &'a IntGauge) -> Self {
+        gauge.inc();
+        GaugeGuard(gauge)
+    }
+}
+
+impl Drop for GaugeGuard<'_> {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// Total size and number of SST files in a RocksDB directory.
+fn disk_usage(path: &Path) -> std::io::Result<(u64, usize)> {
+    let mut bytes = 0;
+    let mut sst_files = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            bytes += metadata.len();
+            if entry.path().extension().is_some_and(|ext| ext == "sst") {
+                sst_files += 1;
+            }
+        }
+    }
+    Ok((bytes, sst_files))
+}
+
+/// Middleware recording request counts and latencies per route.
+///
+/// Needs the `Arc<Metrics>` extension and must be added with `Router::route_layer`,
+/// so the matched route is already known.
+pub async fn track_requests<B>(req: Request<B>, next: Next<B>) -> impl IntoResponse {
+    let start = Instant::now();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_else(|| "unknown".into());
+    let method = req.method().to_string();
+    let metrics = req.extensions().get::<Arc<Metrics>>().cloned();
+
+    let response: Response = next.run(req).await;
+
+    if let Some(metrics) = metrics {
+        metrics
+            .requests
+            .with_label_values(&[&route, &method, response.status().as_str()])
+            .inc();
+        metrics
+            .request_duration
+            .with_label_values(&[&route, &method])
+            .observe(start.elapsed().as_secs_f64());
+    }
+
+    response
+}
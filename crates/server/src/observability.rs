@@ -0,0 +1,107 @@
+use axum::Router;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+use crate::config::{LogFormat, Observability};
+
+/// Where errors end up being reported.
+///
+/// Errors are always emitted as `tracing` events, so with the `Logs` reporter
+/// they only show up in the structured logs. When Sentry is enabled the same
+/// events are also forwarded to it.
+pub enum Reporter {
+    Logs,
+    #[cfg(feature = "sentry")]
+    Sentry {
+        /// Flushes pending events when dropped
+        _guard: sentry::ClientInitGuard,
+    },
+}
+
+impl Reporter {
+    /// Set up logging and error reporting.
+    ///
+    /// Sentry is only enabled if the server was built with the `sentry` feature
+    /// and a DSN is available in `$SENTRY_DSN` or in the config file.
+    pub fn init(config: &Observability) -> Self {
+        let sentry_dsn = std::env::var("SENTRY_DSN")
+            .ok()
+            .or_else(|| config.sentry_dsn.clone())
+            .filter(|dsn| !dsn.is_empty());
+
+        let reporter = match sentry_dsn {
+            #[cfg(feature = "sentry")]
+            Some(dsn) => Reporter::Sentry {
+                _guard: init_sentry(dsn, config),
+            },
+            #[cfg(not(feature = "sentry"))]
+            Some(_) => Reporter::Logs,
+            None => Reporter::Logs,
+        };
+
+        let json_logs = config.log_format == LogFormat::Json;
+        let registry = tracing_subscriber::registry()
+            .with(tracing_subscriber::EnvFilter::new(
+                std::env::var("RUST_LOG").unwrap_or_else(|_| config.log_filter.clone()),
+            ))
+            .with(json_logs.then(|| tracing_subscriber::fmt::layer().json()))
+            .with((!json_logs).then(tracing_subscriber::fmt::layer));
+
+        #[cfg(feature = "sentry")]
+        let registry = registry.with(
+            matches!(reporter, Reporter::Sentry { .. }).then(sentry::integrations::tracing::layer),
+        );
+
+        registry.init();
+
+        match reporter {
+            Reporter::Logs => {
+                #[cfg(not(feature = "sentry"))]
+                if sentry_dsn.is_some() {
+                    tracing::warn!(
+                        "Sentry DSN is set, but mastiff-server was built without Sentry support"
+                    );
+                }
+                tracing::info!("Reporting errors to logs only");
+            }
+            #[cfg(feature = "sentry")]
+            Reporter::Sentry { .. } => tracing::info!("Reporting errors to Sentry"),
+        }
+
+        reporter
+    }
+
+    /// Add the layers needed by the reporter to all routes.
+    pub fn instrument(&self, app: Router) -> Router {
+        match self {
+            Reporter::Logs => app,
+            #[cfg(feature = "sentry")]
+            Reporter::Sentry { .. } => {
+                use sentry::integrations::tower::{NewSentryLayer, SentryHttpLayer};
+
+                // Layers added last run first, so a new Hub is
+                // available when the HTTP transaction starts.
+                app.layer(SentryHttpLayer::with_transaction())
+                    .layer(NewSentryLayer::new_from_top())
+            }
+        }
+    }
+}
+
+#[cfg(feature = "sentry")]
+fn init_sentry(dsn: String, config: &Observability) -> sentry::ClientInitGuard {
+    sentry::init((
+        dsn,
+        sentry::ClientOptions {
+            release: sentry::release_name!(),
+            traces_sample_rate: 1.0,
+            enable_profiling: true,
+            profiles_sample_rate: 1.0,
+            environment: Some(
+                std::env::var("MASTIFF_ENVIRONMENT")
+                    .unwrap_or_else(|_| config.environment.clone())
+                    .into(),
+            ),
+            ..Default::default()
+        },
+    ))
+}
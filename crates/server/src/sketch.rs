@@ -0,0 +1,372 @@
+use std::io::Read;
+
+use axum::body::Bytes;
+use needletail::{parse_fastx_reader, Sequence};
+use sourmash::signature::{Signature, SigsTrait};
+use sourmash::sketch::minhash::{KmerMinHash, KmerMinHashBTree};
+use sourmash::sketch::Sketch;
+use tokio::sync::mpsc::Receiver;
+use tower::BoxError;
+
+/// Sketch FASTA/FASTQ inputs (compressed or not) into a signature compatible with `template`.
+///
+/// All inputs are added to the same sketch. If no name is given the signature
+/// is named after the first record.
+pub fn sketch_sequences<R: Read + Send>(
+    template: &KmerMinHash,
+    name: Option<String>,
+    inputs: impl IntoIterator<Item = R>,
+) -> Result<Signature, BoxError> {
+    // BTree sketches are faster to build, but queries need a regular MinHash
+    let mut mh = KmerMinHashBTree::from(template.clone());
+
+    let mut name = name;
+    let mut records = 0;
+    for input in inputs {
+        let mut parser =
+            parse_fastx_reader(input).map_err(|e| format!("Error reading sequences: {e}"))?;
+        while let Some(record) = parser.next() {
+            let record = record.map_err(|e| format!("Error reading sequences: {e}"))?;
+            mh.add_sequence(&record.normalize(false), true)?;
+            if name.is_none() {
+                name = Some(String::from_utf8_lossy(record.id()).to_string());
+            }
+            records += 1;
+        }
+    }
+
+    if records == 0 {
+        return Err("No sequences found".into());
+    }
+
+    Ok(Signature::builder()
+        .name(name)
+        .signatures(vec![Sketch::MinHash(KmerMinHash::from(mh))])
+        .hash_function("DNA")
+        .build())
+}
+
+/// Get the sketch in `query` to search against an index built with `template`.
+///
+/// Signatures can have sketches for several ksizes, and sketches with a smaller
+/// scaled are downsampled to the index scaled. Returns the sketch together with
+/// a description of what was done to get it, to report to the client.
+pub fn prepare_query(
+    template: &KmerMinHash,
+    query: &Signature,
+) -> Result<(KmerMinHash, Vec<String>), String> {
+    let candidates: Vec<&KmerMinHash> = query
+        .iter()
+        .filter_map(|sketch| match sketch {
+            Sketch::MinHash(mh) => Some(mh),
+            _ => None,
+        })
+        .collect();
+
+    // The largest scaled needs the least downsampling
+    let selected = candidates
+        .iter()
+        .filter(|mh| {
+            mh.ksize() == template.ksize()
+                && mh.hash_function() == template.hash_function()
+                && mh.seed() == template.seed()
+                && mh.scaled() != 0
+                && mh.scaled() <= template.scaled()
+        })
+        .max_by_key(|mh| mh.scaled());
+
+    let Some(mh) = selected else {
+        return Err(format!(
+            "Could not extract compatible sketch to compare. Expected k={}, molecule {} and scaled at most {}",
+            template.ksize(),
+            template.hash_function(),
+            template.scaled(),
+        ));
+    };
+
+    let mut adjustments = vec![];
+    if candidates.len() > 1 {
+        adjustments.push(format!(
+            "Selected the k={} scaled={} sketch out of {} in the signature",
+            mh.ksize(),
+            mh.scaled(),
+            candidates.len()
+        ));
+    }
+
+    if mh.scaled() == template.scaled() {
+        return Ok(((*mh).clone(), adjustments));
+    }
+    let downsampled = mh
+        .downsample_scaled(template.scaled())
+        .map_err(|e| format!("Error downsampling query: {e}"))?;
+    adjustments.push(format!(
+        "Downsampled query from scaled={} to scaled={}",
+        mh.scaled(),
+        template.scaled()
+    ));
+    Ok((downsampled, adjustments))
+}
+
+/// Reads chunks of a request body as they arrive, so it can be sketched
+/// in a blocking thread without buffering the whole body.
+///
+/// Must only be used outside the async runtime, since reads block waiting for chunks.
+pub struct ChunkReader {
+    chunks: Receiver<std::io::Result<Bytes>>,
+    current: Bytes,
+}
+
+impl ChunkReader {
+    pub fn new(chunks: Receiver<std::io::Result<Bytes>>) -> Self {
+        ChunkReader {
+            chunks,
+            current: Bytes::new(),
+        }
+    }
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.current.is_empty() {
+            match self.chunks.blocking_recv() {
+                Some(chunk) => self.current = chunk?,
+                None => return Ok(0),
+            }
+        }
+
+        let len = buf.len().min(self.current.len());
+        buf[..len].copy_from_slice(&self.current.split_to(len));
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sourmash::encodings::HashFunctions;
+    use sourmash::sketch::minhash::max_hash_for_scaled;
+
+    use super::*;
+
+    const SEQ: &str = "ACGTTGCAAGGCTTAACCGGTTAACG";
+
+    /// `>read1` with `SEQ`, gzipped
+    const FASTA_GZ: &[u8] = &[
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xb3, 0x2b, 0x4a, 0x4d, 0x4c,
+        0x31, 0xe4, 0x72, 0x74, 0x76, 0x0f, 0x09, 0x71, 0x77, 0x76, 0x74, 0x74, 0x77, 0x77, 0x0e,
+        0x09, 0x71, 0x74, 0x74, 0x76, 0x76, 0x77, 0x07, 0xd3, 0xee, 0x5c, 0x00, 0xb0, 0x45, 0x8d,
+        0xf3, 0x22, 0x00, 0x00, 0x00,
+    ];
+
+    fn template(track_abundance: bool) -> KmerMinHash {
+        KmerMinHash::new(1, 21, HashFunctions::Murmur64Dna, 42, track_abundance, 0)
+    }
+
+    fn minhash(sig: &Signature) -> &KmerMinHash {
+        match sig.iter().next() {
+            Some(Sketch::MinHash(mh)) => mh,
+            _ => panic!("No MinHash sketch"),
+        }
+    }
+
+    #[test]
+    fn sketch_formats() {
+        let fasta = format!(">read1\n{SEQ}\n");
+        let fastq = format!("@read1\n{SEQ}\n+\n{}\n", "I".repeat(SEQ.len()));
+
+        let from_fasta = sketch_sequences(&template(false), None, [fasta.as_bytes()]).unwrap();
+        let from_fastq = sketch_sequences(&template(false), None, [fastq.as_bytes()]).unwrap();
+        let from_gzip = sketch_sequences(&template(false), None, [FASTA_GZ]).unwrap();
+
+        // 26bp has 6 21-mers
+        assert_eq!(minhash(&from_fasta).size(), 6);
+        assert_eq!(from_fasta.name(), "read1");
+        assert_eq!(minhash(&from_fasta).md5sum(), minhash(&from_fastq).md5sum());
+        assert_eq!(minhash(&from_fasta).md5sum(), minhash(&from_gzip).md5sum());
+    }
+
+    #[test]
+    fn sketch_several_inputs() {
+        let first = format!(">first\n{SEQ}\n");
+        let second = format!(">second\n{SEQ}{SEQ}\n");
+
+        let sig = sketch_sequences(
+            &template(false),
+            Some("sample".into()),
+            [first.as_bytes(), second.as_bytes()],
+        )
+        .unwrap();
+        assert_eq!(sig.name(), "sample");
+        assert!(minhash(&sig).size() > 6);
+        assert!(!minhash(&sig).track_abundance());
+    }
+
+    #[test]
+    fn sketch_abundance() {
+        let fasta = format!(">read1\n{SEQ}\n>read2\n{SEQ}\n");
+
+        let sig = sketch_sequences(&template(true), None, [fasta.as_bytes()]).unwrap();
+        let mh = minhash(&sig);
+        assert!(mh.track_abundance());
+        assert_eq!(mh.abunds().unwrap(), vec![2; 6]);
+    }
+
+    #[test]
+    fn sketch_errors() {
+        assert!(sketch_sequences(&template(false), None, [&b""[..]]).is_err());
+        assert!(sketch_sequences(&template(false), None, [&b"not a fasta file"[..]]).is_err());
+        assert!(sketch_sequences(&template(false), None, Vec::<&[u8]>::new()).is_err());
+    }
+
+    /// Reader for `chunks`, with the sender already dropped
+    fn chunk_reader(chunks: Vec<std::io::Result<Bytes>>) -> ChunkReader {
+        let (tx, rx) = tokio::sync::mpsc::channel(chunks.len().max(1));
+        for chunk in chunks {
+            tx.try_send(chunk).unwrap();
+        }
+        ChunkReader::new(rx)
+    }
+
+    #[test]
+    fn chunk_reader_boundaries() {
+        let mut reader = chunk_reader(vec![
+            Ok(Bytes::from_static(b"ACG")),
+            Ok(Bytes::new()),
+            Ok(Bytes::from_static(b"TTGCA")),
+        ]);
+
+        // Reads stop at chunk boundaries, and empty chunks are skipped
+        let mut buf = [0; 4];
+        assert_eq!(reader.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"ACG");
+        assert_eq!(reader.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"TTGC");
+        assert_eq!(reader.read(&mut buf).unwrap(), 1);
+        assert_eq!(&buf[..1], b"A");
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn chunk_reader_eof() {
+        let mut data = String::new();
+        chunk_reader(vec![]).read_to_string(&mut data).unwrap();
+        assert!(data.is_empty());
+
+        // The sender is dropped while a read is waiting
+        let (tx, rx) = tokio::sync::mpsc::channel(1);
+        let sender = std::thread::spawn(move || {
+            tx.blocking_send(Ok(Bytes::from_static(b">read1\n")))
+                .unwrap();
+        });
+        let mut data = String::new();
+        ChunkReader::new(rx).read_to_string(&mut data).unwrap();
+        sender.join().unwrap();
+        assert_eq!(data, ">read1\n");
+    }
+
+    #[test]
+    fn chunk_reader_error() {
+        let mut reader = chunk_reader(vec![
+            Ok(Bytes::from_static(b">read1\n")),
+            Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionReset,
+                "client went away",
+            )),
+        ]);
+        let mut data = String::new();
+        let err = reader.read_to_string(&mut data).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn sketch_from_chunks() {
+        // Records split across chunks, like a request body arriving over the network
+        let fasta = format!(">read1\n{SEQ}\n");
+        let chunks = fasta
+            .as_bytes()
+            .chunks(5)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        let from_chunks = sketch_sequences(&template(false), None, [chunk_reader(chunks)]).unwrap();
+        let whole = sketch_sequences(&template(false), None, [fasta.as_bytes()]).unwrap();
+        assert_eq!(minhash(&from_chunks).md5sum(), minhash(&whole).md5sum());
+    }
+
+    /// Signature with a DNA sketch for each `(ksize, scaled)`
+    fn query(sketches: &[(u32, u64)], track_abundance: bool) -> Signature {
+        let sketches = sketches
+            .iter()
+            .map(|&(ksize, scaled)| {
+                let mut mh = KmerMinHash::new(
+                    scaled,
+                    ksize,
+                    HashFunctions::Murmur64Dna,
+                    42,
+                    track_abundance,
+                    0,
+                );
+                for hash in [1, 2, max_hash_for_scaled(1000) + 1] {
+                    mh.add_hash_with_abundance(hash, 5);
+                }
+                Sketch::MinHash(mh)
+            })
+            .collect();
+        Signature::builder()
+            .name(Some("query".into()))
+            .signatures(sketches)
+            .hash_function("DNA")
+            .build()
+    }
+
+    fn index_template(scaled: u64) -> KmerMinHash {
+        KmerMinHash::new(scaled, 31, HashFunctions::Murmur64Dna, 42, false, 0)
+    }
+
+    #[test]
+    fn prepare_query_same_params() {
+        let (mh, adjustments) =
+            prepare_query(&index_template(1000), &query(&[(31, 1000)], false)).unwrap();
+        assert_eq!((mh.ksize(), mh.scaled(), mh.size()), (31, 1000, 2));
+        assert!(adjustments.is_empty());
+    }
+
+    #[test]
+    fn prepare_query_selects_ksize() {
+        let sig = query(&[(21, 1000), (31, 1000), (51, 1000)], false);
+        let (mh, adjustments) = prepare_query(&index_template(1000), &sig).unwrap();
+        assert_eq!((mh.ksize(), mh.scaled()), (31, 1000));
+        assert_eq!(
+            adjustments,
+            ["Selected the k=31 scaled=1000 sketch out of 3 in the signature"]
+        );
+    }
+
+    #[test]
+    fn prepare_query_downsamples() {
+        // The largest scaled allowed needs the least downsampling
+        let sig = query(&[(31, 10), (31, 100), (31, 10000)], false);
+        let (mh, adjustments) = prepare_query(&index_template(1000), &sig).unwrap();
+        assert_eq!((mh.scaled(), mh.mins()), (1000, vec![1, 2]));
+        assert_eq!(
+            adjustments,
+            [
+                "Selected the k=31 scaled=100 sketch out of 3 in the signature",
+                "Downsampled query from scaled=100 to scaled=1000",
+            ]
+        );
+    }
+
+    #[test]
+    fn prepare_query_rejects_larger_scaled() {
+        let err = prepare_query(&index_template(1000), &query(&[(31, 10000)], false)).unwrap_err();
+        assert!(err.contains("scaled at most 1000"), "{err}");
+        assert!(prepare_query(&index_template(1000), &query(&[(21, 1000)], false)).is_err());
+    }
+
+    #[test]
+    fn prepare_query_keeps_abundances() {
+        let (mh, _) = prepare_query(&index_template(1000), &query(&[(31, 100)], true)).unwrap();
+        assert!(mh.track_abundance());
+        assert_eq!(mh.abunds(), Some(vec![5, 5]));
+    }
+}
@@ -8,7 +8,10 @@
 
     nixosModule = { config, lib, pkgs, ... }:
       with lib;
-      let cfg = config.mastiff.services.api;
+      let
+        cfg = config.mastiff.services.api;
+        settingsFormat = pkgs.formats.toml { };
+        configFile = settingsFormat.generate "mastiff.toml" cfg.settings;
       in {
         options.mastiff.services.api = {
           enable = mkEnableOption "Enables the mastiff HTTP service";
@@ -19,9 +22,24 @@
             example = default;
             description = "Location of the mastiff DB to serve";
           };
+
+          settings = mkOption {
+            type = settingsFormat.type;
+            default = { };
+            description = ''
+              mastiff-server configuration.
+              See crates/server/mastiff.example.toml for available options.
+            '';
+          };
         };
 
         config = mkIf cfg.enable {
+          mastiff.services.api.settings.indexes = mkDefault [{
+            name = "sra";
+            path = cfg.domain;
+            ksize = 21;
+          }];
+
           systemd.services."mastiff.api" = {
             wantedBy = [ "multi-user.target" ];
 
@@ -29,7 +47,7 @@
               let pkg = mastiff.packages.${pkgs.system}.default;
               in {
                 Restart = "on-failure";
-                ExecStart = "${pkg}/bin/mastiff-server -k21 /scratch";
+                ExecStart = "${pkg}/bin/mastiff-server --config ${configFile}";
                 DynamicUser = "yes";
                 RuntimeDirectory = "mastiff.api";
                 RuntimeDirectoryMode = "0755";